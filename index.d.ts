@@ -3,13 +3,435 @@
 
 /* auto-generated by NAPI-RS */
 
+export interface PtyOptions {
+  command: string
+  args: Array<string>
+  envs: Record<string, string>
+  dir: string
+  size: Size
+  onExit?: (err: null | Error, exitCode: number) => void
+  /**
+   * Process group to place the child in. When unset, the child becomes the leader of a new
+   * session (and process group) with the pty as its controlling terminal. `0` creates a new
+   * group led by the child, any other value joins that existing group; both skip `setsid`, so
+   * the child stays in the caller's session and the pty is *not* its controlling terminal.
+   */
+  pgid?: number
+  /**
+   * Whether closing the controller fd should hang up the child (the kernel sends `SIGHUP` to
+   * the session of a pty's controlling terminal). Defaults to `true`. When `false`, the child
+   * still gets its own session but the pty never becomes its controlling terminal, so it
+   * survives the controller being closed. The tradeoff is that the child has no job control:
+   * typing `^C`/`^Z` doesn't signal it and opening `/dev/tty` fails.
+   */
+  hangupOnClose?: boolean
+  /**
+   * Enables packet mode (`TIOCPKT`) on the controller. In packet mode every read from the
+   * controller fd is prefixed with a control byte: `0` (`TIOCPKT_DATA`) followed by regular
+   * output, or a bitmask of `TIOCPKT_*` flags reporting flush and flow-control events on the
+   * child's side, with no data following it. Consumers must strip/interpret that byte.
+   */
+  packetMode?: boolean
+  /**
+   * Disables output post-processing (`OPOST`), so the child's `\n` isn't translated to `\r\n`.
+   * Useful when capturing output for storage rather than rendering it in a terminal emulator.
+   * Programs that reset the termios themselves (e.g. `stty sane`) re-enable it.
+   */
+  rawOutput?: boolean
+  /**
+   * Whether to reap the child on a dedicated wait thread (the default). Embedders with their
+   * own `SIGCHLD` handling can set this to `false` to avoid double-waiting, and drive reaping
+   * themselves through `try_reap()`, which also fires `on_exit`. Nothing else reaps the child
+   * then, not even once the `Pty` was garbage collected.
+   */
+  manageWait?: boolean
+  /**
+   * How to wire the child's stdio: `"pty"` (the default) or `"pipe"`. In pipe mode stdout and
+   * stderr go to a pipe whose read end is `fd`, stdin comes from a pipe whose write end is
+   * `input_fd`, and the child gets a new session without a controlling terminal. Terminal-only
+   * features (`resize`, `packet_mode`, `raw_output`, ...) aren't available in pipe mode.
+   */
+  mode?: 'pty' | 'pipe'
+  /**
+   * Filesystem setup to run in the child right before `exec`, in order, e.g. to create a
+   * directory the command expects. Relative paths resolve against `dir`, which has to exist
+   * already. The first step that fails aborts the spawn with its error.
+   */
+  prepare?: Array<PrepareStep>
+  /**
+   * An fd open for writing on a cgroup (v2) `cgroup.procs` file, which the child writes its own
+   * pid into before `exec`. Unlike `move_to_cgroup`, this needs no access to the cgroup's path
+   * and leaves no window where the child runs outside of it. The fd stays owned by the caller,
+   * the command itself doesn't inherit it. Can't be one of stdin, stdout or stderr. Linux only.
+   */
+  cgroupProcsFd?: number
+  /**
+   * Routes the child's stderr to a separate pipe whose read end is `stderr_fd`, instead of
+   * merging it with stdout. In pty mode stdout stays on the pty, so the child still sees a
+   * terminal there, but stderr no longer is one (e.g. `isatty(2)` is false). Shorthand for
+   * `stderr: "pipe"`, so it can't be combined with any other `stderr`.
+   */
+  splitStderr?: boolean
+  /**
+   * Requested capacity of the output pipe in bytes (`F_SETPIPE_SZ`), e.g. to cut down on
+   * wakeups when capturing lots of output. The kernel rounds it up to a power-of-two number of
+   * pages, and unprivileged processes can't go beyond `/proc/sys/fs/pipe-max-size`. Only
+   * supported in pipe mode (a pty's buffer size is fixed) and on Linux.
+   */
+  pipeSize?: number
+  /**
+   * Value of `TERM` for the child. In pty mode it defaults to `xterm-256color` when the parent
+   * has no `TERM` to pass down, so that curses programs work out of the box. An entry in `envs`
+   * takes precedence.
+   */
+  term?: string
+  /**
+   * Whether to set `COLUMNS` and `LINES` in the child's environment from the initial `size`,
+   * for programs that don't query the terminal size. Entries in `envs` take precedence.
+   */
+  sizeEnv?: boolean
+  /**
+   * Extra environment variables as raw `KEY=VALUE` entries, like `environ`, applied in order
+   * after `envs` (so they take precedence over it, and later entries over earlier ones).
+   */
+  envPairs?: Array<string>
+  /**
+   * Variables to remove from the child's environment, e.g. secrets the parent has but the child
+   * shouldn't inherit. Applied last, so this also drops entries from `envs` and `env_pairs`.
+   */
+  unsetEnvs?: Array<string>
+  /**
+   * Caps how much output is read natively: what `run()` collects, or what `on_data` is called
+   * with. Once reached, the rest is read and discarded (or the child is killed, with
+   * `kill_on_output_limit`), and `run()` marks its result as truncated. Without `on_data`, a
+   * `new Pty(...)` leaves reading `fd` to the consumer, so it rejects this.
+   */
+  maxOutputBytes?: number
+  /**
+   * Whether to kill the child's process group (`SIGKILL`) once `max_output_bytes` is reached,
+   * instead of letting it finish.
+   */
+  killOnOutputLimit?: boolean
+  /**
+   * A cgroup (v2) directory whose accounting is read as soon as the child is reaped and
+   * reported in `ExitInfo` (`memory.peak`, `cpu.stat`), before a supervisor gets to tear the
+   * cgroup down. This doesn't put the child there, combine it with `cgroup_procs_fd` or
+   * `move_to_cgroup`. Stats that can't be read are left unset. Linux only.
+   */
+  cgroupPath?: string
+  /**
+   * An fd open for writing, e.g. a log file, to hand to the child as fd `log_fd_target`, for
+   * commands that expect to find one there. The child gets its own duplicate without
+   * `FD_CLOEXEC`, the fd itself stays owned by the caller. Can't be one of stdin, stdout or
+   * stderr, which are already the child's own by then.
+   */
+  logFd?: number
+  /** Fd number the child gets `log_fd` as. Defaults to `3`, stdio (`0`-`2`) can't be replaced. */
+  logFdTarget?: number
+  /**
+   * Called with every chunk of output read from the controller (the pty, or the output pipe in
+   * pipe mode), for consumers that would rather not read `fd` themselves. A native thread does
+   * the reading and stops while chunks are waiting to be delivered, so a busy event loop slows
+   * down the child instead of buffering without bound. Reading `fd` directly as well steals
+   * output from the callback, and the fd can't be taken with `take_fd`. Not supported by `run()`.
+   */
+  onData?: (data: Buffer) => void
+  /**
+   * Fixed input for the child: its stdin becomes a pipe that yields this data followed by EOF,
+   * for commands that read until the end of their input. In pty mode stdout (and stderr) stay on
+   * the pty, so the child still formats its output for a terminal, but stdin is no longer one
+   * (`isatty(0)` is false). Writes to `input_fd` then only reach the terminal (e.g. programs
+   * reading `/dev/tty`) in pty mode, and fail in pipe mode.
+   */
+  stdinData?: Buffer
+  /**
+   * File to append a copy of all output to, as it is read natively by `on_data` or `run()`
+   * (which one of those is required). Created if it doesn't exist.
+   */
+  outputLogPath?: string
+  /**
+   * Enables `push_input`. Off by default because `TIOCSTI` is a well known privilege escalation
+   * vector: anything that can push input into a terminal can run commands as whoever reads from
+   * it. Only turn this on for trusted local automation.
+   */
+  allowPushInput?: boolean
+  /**
+   * Where the child's stdin comes from: `"pty"`, `"pipe"` (written to through `input_fd`),
+   * `"null"` (`/dev/null`) or `"inherit"` (this process' stdin). Defaults to `mode`. In pty mode
+   * a pipe takes the terminal's place on stdin only, the child can still open `/dev/tty`.
+   */
+  stdin?: 'pty' | 'pipe' | 'null' | 'inherit'
+  /**
+   * Where the child's stdout goes, like `stdin`. `"pipe"` is the output pipe read through `fd` in
+   * pipe mode. In pty mode it's a pipe of its own read through `stdout_fd`, and stdout is no
+   * longer a terminal while stdin and stderr can still be.
+   */
+  stdout?: 'pty' | 'pipe' | 'null' | 'inherit'
+  /**
+   * Where the child's stderr goes, like `stdout`. `"pipe"` is a pipe of its own read through
+   * `stderr_fd` in either mode. Defaults to the pty in pty mode and to the output pipe behind `fd`
+   * (shared with stdout) in pipe mode.
+   */
+  stderr?: 'pty' | 'pipe' | 'null' | 'inherit'
+  /**
+   * `argv[0]` for the child instead of `command`, for programs that look at the name they were
+   * invoked as, e.g. `-bash` to start a login shell or an applet name for busybox.
+   */
+  argv0?: string
+}
+/** A single `PtyOptions::prepare` step. */
+export interface PrepareStep {
+  /**
+   * `"mkdir"` creates a directory (succeeding if it already exists), `"touch"` creates an empty
+   * file if it doesn't exist, `"chmod"` changes the permissions of an existing path.
+   */
+  op: 'mkdir' | 'touch' | 'chmod'
+  path: string
+  /**
+   * Permission bits. Required for `"chmod"`, defaults to `0o755` for `"mkdir"` and `0o644` for
+   * `"touch"` (both subject to the umask).
+   */
+  mode?: number
+}
 export interface Size {
   cols: number
   rows: number
 }
+/** How the child exited. */
+export interface ExitInfo {
+  /** Exit code if the child exited normally, unset if it was killed by a signal. */
+  exitCode?: number
+  /** Signal that killed the child, unset if it exited normally. */
+  signal?: number
+  coreDumped: boolean
+  /** Peak memory usage of `cgroup_path` in bytes (`memory.peak`). */
+  memoryPeak?: number
+  /** Total, user and system CPU time of `cgroup_path` in microseconds (`cpu.stat`). */
+  cpuUsageUsec?: number
+  cpuUserUsec?: number
+  cpuSystemUsec?: number
+}
+/** What `run` returns. */
+export interface RunResult {
+  /** Exit code of the child, `-1` if it was killed by a signal (like `on_exit`). */
+  exitCode: number
+  /**
+   * Everything the child wrote to the pty (or to the output pipe in pipe mode), up to
+   * `max_output_bytes`.
+   */
+  output: Buffer
+  /** Whether output was dropped because of `max_output_bytes`. */
+  truncated: boolean
+}
+/** A snapshot of the child's `/proc/<pid>/stat`. */
+export interface ProcStat {
+  /** Single-character process state (`R`, `S`, `D`, `Z`, `T`, ...). */
+  state: string
+  /** CPU time spent in user mode, in milliseconds. */
+  utime: number
+  /** CPU time spent in kernel mode, in milliseconds. */
+  stime: number
+  /** Resident set size, in bytes. */
+  rss: number
+}
+/** Fds handed over by `Pty::take_fds`. */
+export interface TakenFds {
+  controller: number
+  /** The pty's user side, unset in pipe mode. */
+  user?: number
+}
 export class Pty {
   fd: number
+  /** Fd to write the child's input to. Same as `fd` in pty mode, unless stdin is a pipe. */
+  inputFd: number
+  /**
+   * Fd to read the child's stdout from, only set with `stdout: "pipe"` in pty mode (in pipe mode
+   * that is `fd`).
+   */
+  stdoutFd?: number
+  /** Fd to read the child's stderr from, only set with `stderr: "pipe"` (or `split_stderr`). */
+  stderrFd?: number
   pid: number
-  constructor(command: string, args: Array<string>, envs: Record<string, string>, dir: string, size: Size, onExit: (err: null | Error, exitCode: number) => void)
+  pgid: number
+  constructor(opts: PtyOptions)
+  /**
+   * Spawns a fresh child with the same options as this one, e.g. to restart a supervised process
+   * after it exited. This `Pty` is left untouched. Callbacks aren't kept around for this, since
+   * they commonly refer to the `Pty` itself and would keep it from being garbage collected, so
+   * `on_exit` and `on_data` have to be passed again.
+   */
+  respawn(onExit?: (err: null | Error, exitCode: number) => void, onData?: (data: Buffer) => void): Pty
+  /**
+   * Sets the callback fired once the child is reaped, e.g. after taking over a `Pty` from code
+   * that didn't pass one. Only one is kept: this replaces the one from the options or from an
+   * earlier call, as long as that didn't fire yet. If the child was already reaped, it fires
+   * right away.
+   */
+  onExit(callback: (err: null | Error, exitCode: number) => void): void
   resize(size: Size): void
+  /**
+   * Re-applies the size the pty was created with and sends `SIGWINCH` to the child's process
+   * group, e.g. when reattaching to it. The kernel only signals the child itself when the size
+   * actually changes, so the signal is sent explicitly to make it redraw either way.
+   */
+  resetSize(): void
+  /**
+   * Resizes the pty to the `COLUMNS` and `LINES` found in `env`, e.g. a shell's environment, to
+   * sync the pty with it when reattaching. A missing variable keeps that dimension as it is,
+   * values beyond what a window size can hold are clamped, anything but a positive integer is
+   * rejected.
+   */
+  resizeFromEnv(env: Record<string, string>): void
+  /**
+   * Returns a Promise that resolves once the controller fd is readable (or the child side has
+   * hung up), without reading anything from it.
+   *
+   * This is one-shot: every call arms a single `poll` on a helper thread, so consumers should
+   * drain the fd with their own nonblocking reads and then call `readable()` again. Since no
+   * data is consumed here, backpressure stays entirely with the caller.
+   */
+  readable(): Promise<void>
+  /** Reads the child's current state from `/proc/<pid>/stat`. Linux only. */
+  procStat(): ProcStat
+  /**
+   * Returns the child's current working directory from `/proc/<pid>/cwd`, e.g. to follow a shell
+   * that changed directories. Linux only.
+   */
+  childCwd(): string
+  /**
+   * Writes the whole buffer to the controller fd on a worker thread, waiting for the child to
+   * drain its input whenever the fd would block. Resolves once every byte has been written and
+   * rejects with the underlying error otherwise.
+   */
+  writeAll(data: Buffer): Promise<void>
+  /**
+   * Returns the controller fd without transferring ownership. The fd is only valid while this
+   * `Pty` is alive and until `wait_closed()` resolves (it is closed once the child is reaped and
+   * its output was drained), and it must not be closed by the caller.
+   */
+  borrowFd(): number
+  /**
+   * Moves the child into another cgroup (v2) by writing its pid into
+   * `<cgroup_path>/cgroup.procs`. Linux only.
+   */
+  moveToCgroup(cgroupPath: string): void
+  /**
+   * Returns the device number (`st_rdev`) of the pty's user side, e.g. to correlate it with
+   * `/dev/pts/N` or the `tty_nr` field of `/proc/<pid>/stat`.
+   */
+  ttyDev(): number
+  /**
+   * Checks whether the child has exited without blocking (`WNOHANG`), for use with
+   * `manage_wait: false`. Once the child is reaped this fires `on_exit` and, like the wait
+   * thread would, closes the controller fd after its remaining output was read (or at most a
+   * second later). Returns the exit code, or `null` while the child is still running.
+   */
+  tryReap(): number | null
+  /**
+   * Returns a Promise that resolves with how the child exited, once it has been reaped (by the
+   * wait thread, or by `try_reap()` when `manage_wait` is false). Resolves immediately if the
+   * child already exited.
+   */
+  wait(): Promise<ExitInfo>
+  /**
+   * Returns a Promise that resolves once the child has exited *and* the controller fd was
+   * closed, i.e. the point after which no more output will arrive.
+   *
+   * The controller is only closed once the child's side has hung up and everything it wrote was
+   * read from the fd (or at most a second after the child was reaped, if the output isn't read or
+   * a grandchild keeps the pty open), so consumers that keep reading until this resolves don't
+   * lose the last lines of output. With `manage_wait: false` that starts with the `try_reap()`
+   * call that reaps the child, and after `take_fd()` closing the fd is up to the caller, so this
+   * only waits for the child to exit.
+   */
+  waitClosed(): Promise<void>
+  /**
+   * Sends `SIGTERM` to the child and, if it hasn't exited after `grace_ms`, `SIGKILL`.
+   * Resolves with the final exit code (`-1` when killed by a signal, like `on_exit`). Calling
+   * this on a child that already exited just resolves with its exit code. Requires the wait
+   * thread, i.e. `manage_wait` must not be false.
+   */
+  terminate(graceMs: number): Promise<number>
+  /**
+   * Stops the child's process group with `SIGSTOP`, e.g. to pause a runaway process without
+   * killing it. Note that with an explicit `pgid` this affects every process in that group.
+   */
+  suspend(): void
+  /** Continues the child's process group with `SIGCONT` after `suspend()`. */
+  resumeProcess(): void
+  /**
+   * Whether the child leads its own session (`getsid(pid) == pid`), i.e. whether the `setsid`
+   * done before `exec` took effect. False when spawned with an explicit `pgid`, or if the child
+   * moved itself into another session.
+   */
+  isSessionLeader(): boolean
+  /**
+   * Issues a raw `ioctl` on the controller fd and returns its result. This is an escape hatch
+   * for requests the crate doesn't wrap, and it is restricted to a safelist of requests that
+   * take no argument or an integer value (e.g. `TIOCSIG`, `TCFLSH`, `TIOCSBRK`), since
+   * pointer-taking requests can't be expressed safely from JS. Misusing even these can break
+   * the terminal, so prefer the dedicated methods where they exist.
+   */
+  ioctl(request: number, arg?: number | undefined | null): number
+  /** Returns how many bytes of the child's output are waiting to be read from the controller fd. */
+  pendingOutput(): number
+  /** Discards the child's output that hasn't been read from the controller fd yet. */
+  flushOutput(): void
+  /**
+   * Discards input that was written to the pty but not yet read by the child, including a
+   * partially typed line in canonical mode.
+   */
+  flushInput(): void
+  /** Returns the line speed (baud rate) the terminal reports, e.g. `38400` by default. */
+  getSpeed(): number
+  /**
+   * Sets the line speed (baud rate) the terminal reports, for both input and output. A pty
+   * doesn't get any slower or faster, but programs that query the speed (e.g. to pace their
+   * output, or serial tools) behave accordingly. Only standard rates are accepted.
+   */
+  setSpeed(baud: number): void
+  /**
+   * Injects `data` into the pty's input queue byte by byte with `TIOCSTI`, as if it had been typed
+   * on the terminal. Requires `allow_push_input`; see there for why this is dangerous.
+   * The kernel additionally requires `CAP_SYS_ADMIN` (the pty isn't the caller's controlling
+   * terminal), and Linux 6.2+ refuses it altogether unless `dev.tty.legacy_tiocsti` is enabled.
+   */
+  pushInput(data: Buffer): void
+  /**
+   * Transfers ownership of the controller fd to the caller, who becomes responsible for closing
+   * it. It is no longer closed when the child exits, and methods that need it will error.
+   */
+  takeFd(): number
+  /** Whether ownership of the controller fd was transferred with `take_fd`. */
+  fdTaken(): boolean
+  /**
+   * Like `take_fd`, but also hands over a fresh fd for the pty's user side (opened with
+   * `O_NOCTTY`), e.g. to re-parent the tty. The parent's original copy of the user side is closed
+   * right after spawning, so this is a new handle; `user` is unset in pipe mode, where there is
+   * no tty. Either both fds are transferred or neither is. Note that while the caller keeps the
+   * user fd open, the controller won't see a hangup when the child exits.
+   */
+  takeFds(): TakenFds
+  /** The command the child was spawned with, as passed in the options. */
+  get command(): string
+  /** The arguments the child was spawned with. */
+  get args(): Array<string>
+  /** The child's initial working directory, resolved to an absolute path. */
+  get dir(): string
 }
+/**
+ * Spawns a child like `new Pty(opts)`, reads all of its output until EOF, waits for it to exit
+ * and returns both. This **blocks** the calling thread (and so the JS event loop) until then,
+ * which is fine for scripts but not for servers. The child gets no input (in pty mode it should
+ * not try to read any), `manage_wait` is ignored, and `on_exit` (if any) still fires once the
+ * child is reaped. EOF only arrives once every process holding the pty open has closed it,
+ * including backgrounded grandchildren. Only `fd` is read, so stderr (and, in pty mode, stdout)
+ * can't be a pipe of its own.
+ */
+export function run(opts: PtyOptions): RunResult
+/**
+ * Returns how many `Pty` objects are currently alive, i.e. were constructed and not yet garbage
+ * collected. A number that keeps growing in a long-running process points at `Pty`s (and their
+ * fds) being kept around after their child exited.
+ */
+export function livePtyCount(): number
@@ -295,6 +295,8 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { Pty } = nativeBinding
+const { Pty, run, livePtyCount } = nativeBinding
 
 module.exports.Pty = Pty
+module.exports.run = run
+module.exports.livePtyCount = livePtyCount
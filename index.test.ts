@@ -1,5 +1,5 @@
 import fs from 'fs';
-import { Pty } from './index';
+import { Pty, livePtyCount, run, type PtyOptions } from './index';
 
 describe('PTY', () => {
   const CWD = process.cwd();
@@ -7,18 +7,18 @@ describe('PTY', () => {
   test('spawns and exits', (done) => {
     const message = 'hello from a pty';
 
-    const pty = new Pty(
-      '/bin/echo',
-      [message],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      (err, exitCode) => {
+    const pty = new Pty({
+      command: '/bin/echo',
+      args: [message],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
         expect(err).toBeNull();
         expect(exitCode).toBe(0);
         done();
       },
-    );
+    });
 
     const readStream = fs.createReadStream('', { fd: pty.fd });
 
@@ -28,31 +28,146 @@ describe('PTY', () => {
   });
 
   test('captures an exit code', (done) => {
-    new Pty(
-      '/bin/sh',
-      ['-c', 'exit 17'],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      (err, exitCode) => {
+    new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'exit 17'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
         expect(err).toBeNull();
         expect(exitCode).toBe(17);
         done();
       },
+    });
+  });
+
+  test('resolves wait() with structured exit info', async () => {
+    const exited = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'exit 3'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(await exited.wait()).toEqual({ exitCode: 3, coreDumped: false });
+
+    const killed = new Pty({
+      command: '/bin/sleep',
+      args: ['10'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    process.kill(killed.pid, 'SIGTERM');
+
+    expect(await killed.wait()).toEqual({ signal: 15, coreDumped: false });
+  });
+
+  test('reports cgroup stats on exit', async () => {
+    // Stand-in for a cgroup directory, only the stat files are read.
+    const cgroup = fs.mkdtempSync('/tmp/ruspty-cgroup-');
+    fs.writeFileSync(`${cgroup}/memory.peak`, '1048576\n');
+    fs.writeFileSync(
+      `${cgroup}/cpu.stat`,
+      'usage_usec 1500\nuser_usec 1000\nsystem_usec 500\nnr_periods 0\n',
     );
+
+    const pty = new Pty({
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      cgroupPath: cgroup,
+    });
+
+    expect(await pty.wait()).toEqual({
+      exitCode: 0,
+      coreDumped: false,
+      memoryPeak: 1048576,
+      cpuUsageUsec: 1500,
+      cpuUserUsec: 1000,
+      cpuSystemUsec: 500,
+    });
+
+    // Missing stats are left unset.
+    fs.rmSync(`${cgroup}/cpu.stat`);
+    const again = new Pty({
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      cgroupPath: cgroup,
+    });
+
+    expect(await again.wait()).toEqual({
+      exitCode: 0,
+      coreDumped: false,
+      memoryPeak: 1048576,
+    });
+
+    fs.rmSync(cgroup, { recursive: true });
   });
 
+  test('escalates to SIGKILL when terminate() times out', async () => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'trap "" TERM; sleep 10'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    // Give the shell a moment to install the trap.
+    await new Promise((resolve) => setTimeout(resolve, 100));
+
+    expect(await pty.terminate(100)).toBe(-1);
+    expect(await pty.wait()).toEqual({ signal: 9, coreDumped: false });
+    // Already dead, nothing left to signal.
+    expect(await pty.terminate(100)).toBe(-1);
+  });
+
+  test.if(process.platform === 'linux')(
+    'passes safelisted ioctls through',
+    async () => {
+      const TIOCSIG = 0x40045436;
+      const FIONREAD = 0x541b;
+
+      const pty = new Pty({
+        command: '/bin/sleep',
+        args: ['10'],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+      });
+
+      expect(() => pty.ioctl(FIONREAD)).toThrow('not allowed');
+
+      pty.ioctl(TIOCSIG, 2);
+      expect(await pty.wait()).toEqual({ signal: 2, coreDumped: false });
+
+      await pty.waitClosed();
+      expect(() => pty.ioctl(TIOCSIG, 2)).toThrow(
+        'controller fd was closed after the child exited',
+      );
+    },
+  );
+
   test('can be written to', (done) => {
     const message = 'hello cat';
 
-    const pty = new Pty(
-      '/bin/cat',
-      [],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      () => {},
-    );
+    const pty = new Pty({
+      command: '/bin/cat',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
 
     const readStream = fs.createReadStream('', { fd: pty.fd });
     const writeStream = fs.createWriteStream('', { fd: pty.fd });
@@ -65,15 +180,171 @@ describe('PTY', () => {
     writeStream.write(message);
   });
 
-  test('can be resized', (done) => {
-    const pty = new Pty(
-      '/bin/sh',
-      [],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      () => {},
+  test('can write large buffers with writeAll', (done) => {
+    let written = false;
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'stty -echo; wc -c > /dev/null'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
+        // `wc` only exits once it has consumed the trailing ^D, i.e. the whole buffer.
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        expect(written).toBe(true);
+        done();
+      },
+    });
+
+    const line = 'x'.repeat(99) + '\n';
+    const data = Buffer.from(line.repeat(5000) + '\x04');
+
+    pty.writeAll(data).then(() => {
+      written = true;
+    });
+  });
+
+  test('can spawn with plain pipes', (done) => {
+    const message = 'hello pipe';
+
+    const pty = new Pty({
+      command: '/bin/cat',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      mode: 'pipe',
+    });
+
+    expect(pty.inputFd).not.toBe(pty.fd);
+    expect(() => pty.resize({ rows: 60, cols: 100 })).toThrow();
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+
+    readStream.on('data', (chunk) => {
+      // No echo and no CRLF translation, the data comes back exactly as written.
+      expect(chunk.toString()).toBe(message + '\n');
+      done();
+    });
+
+    fs.writeSync(pty.inputFd, message + '\n');
+  });
+
+  test('can inspect and flush pending data', async () => {
+    const sleep = (ms: number) =>
+      new Promise((resolve) => setTimeout(resolve, ms));
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'stty -echo; echo 12345; sleep 0.3; read x; echo "[$x]"'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    await sleep(100);
+    expect(pty.pendingOutput()).toBe('12345\r\n'.length);
+    pty.flushOutput();
+    expect(pty.pendingOutput()).toBe(0);
+
+    fs.writeSync(pty.fd, 'discarded');
+    pty.flushInput();
+    fs.writeSync(pty.fd, 'kept\n');
+
+    await sleep(400);
+    expect(pty.pendingOutput()).toBe('[kept]\r\n'.length);
+  });
+
+  test('delivers output to onData', (done) => {
+    let output = '';
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'seq 1 3000'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onData: (data) => {
+        output += data.toString();
+
+        if (output.endsWith('3000\r\n')) {
+          expect(output.split('\r\n')).toHaveLength(3001);
+          done();
+        }
+      },
+    });
+
+    expect(() => pty.takeFd()).toThrow('on_data');
+  });
+
+  test('only pushes input when allowed', () => {
+    const pty = new Pty({
+      command: '/bin/cat',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(() => pty.pushInput(Buffer.from('hi\n'))).toThrow(
+      'push_input requires allow_push_input',
+    );
+    process.kill(pty.pid, 'SIGKILL');
+  });
+
+  test("doesn't push input once the controller was closed", async () => {
+    const pty = new Pty({
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      allowPushInput: true,
+    });
+
+    await pty.waitClosed();
+    expect(() => pty.pushInput(Buffer.from('hi\n'))).toThrow(
+      'controller fd was closed after the child exited',
     );
+  });
+
+  // `TIOCSTI` needs `CAP_SYS_ADMIN` for a terminal that isn't our own.
+  test.if(process.getuid?.() === 0)('can push input as if typed', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'read line; echo "got $line"'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      allowPushInput: true,
+      onExit: () => {},
+    });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+
+    let buffer = '';
+    readStream.on('data', (chunk) => {
+      buffer += chunk.toString();
+
+      if (buffer.includes('got hi\r\n')) {
+        done();
+      }
+    });
+
+    pty.pushInput(Buffer.from('hi\n'));
+  });
+
+  test('can be resized', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
 
     const readStream = fs.createReadStream('', { fd: pty.fd });
     const writeStream = fs.createWriteStream('', { fd: pty.fd });
@@ -99,20 +370,1086 @@ describe('PTY', () => {
     writeStream.write("stty size; echo 'done1'\n");
   });
 
-  test('respects working directory', (done) => {
-    const pty = new Pty(
-      '/bin/pwd',
-      [],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      (err, exitCode) => {
+  test('can reset to the initial size', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+    const writeStream = fs.createWriteStream('', { fd: pty.fd });
+
+    let buffer = '';
+
+    readStream.on('data', (chunk) => {
+      buffer += chunk.toString();
+
+      if (buffer.includes('ready\r\n')) {
+        // The size doesn't change, so only the explicit `SIGWINCH` reaches the trap.
+        buffer = '';
+        pty.resetSize();
+        writeStream.write("echo 'done1'\n");
+      }
+
+      if (buffer.includes('done1\r\n')) {
+        expect(buffer).toContain('winch');
+        pty.resize({ rows: 60, cols: 100 });
+        pty.resetSize();
+        buffer = '';
+        writeStream.write("stty size; echo 'done2'\n");
+      }
+
+      if (buffer.includes('done2\r\n')) {
+        expect(buffer).toContain('24 80');
+        done();
+      }
+    });
+
+    writeStream.write("trap 'echo winch' WINCH; echo 'ready'\n");
+  });
+
+  test('can set the line speed', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
+
+    expect(pty.getSpeed()).toBe(38400);
+    expect(() => pty.setSpeed(12345)).toThrow('unsupported baud rate');
+    pty.setSpeed(9600);
+    expect(pty.getSpeed()).toBe(9600);
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+    const writeStream = fs.createWriteStream('', { fd: pty.fd });
+
+    let buffer = '';
+    readStream.on('data', (chunk) => {
+      buffer += chunk.toString();
+
+      if (buffer.includes('done\r\n')) {
+        expect(buffer).toContain('\r\n9600\r\n');
+        done();
+      }
+    });
+
+    writeStream.write("stty speed; echo 'done'\n");
+  });
+
+  test('can be resized from COLUMNS and LINES', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
+
+    expect(() => pty.resizeFromEnv({ COLUMNS: 'wide' })).toThrow(
+      'invalid COLUMNS',
+    );
+    expect(() => pty.resizeFromEnv({ TERM: 'dumb' })).toThrow(
+      'neither COLUMNS nor LINES is set',
+    );
+    pty.resizeFromEnv({ LINES: '60' });
+    pty.resizeFromEnv({ COLUMNS: '100000' });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+    const writeStream = fs.createWriteStream('', { fd: pty.fd });
+
+    let buffer = '';
+    readStream.on('data', (chunk) => {
+      buffer += chunk.toString();
+
+      if (buffer.includes('done\r\n')) {
+        expect(buffer).toContain('\r\n60 65535\r\n');
+        done();
+      }
+    });
+
+    writeStream.write("stty size; echo 'done'\n");
+  });
+
+  test('resolves readable once output is available', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'sleep 0.1; echo ready'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
+
+    pty.readable().then(() => {
+      done();
+    });
+  });
+
+  test('works without an exit callback', (done) => {
+    const message = 'no callback';
+
+    const pty = new Pty({
+      command: '/bin/cat',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+    const writeStream = fs.createWriteStream('', { fd: pty.fd });
+
+    readStream.on('data', (chunk) => {
+      expect(chunk.toString()).toBe(message);
+      done();
+    });
+
+    writeStream.write(message);
+  });
+
+  test('can set the exit callback later', async () => {
+    const spawn = () =>
+      new Pty({
+        command: '/bin/sh',
+        args: ['-c', 'exit 5'],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        onExit: () => {
+          throw new Error('replaced callback must not fire');
+        },
+      });
+    const exitCodeOf = (pty: Pty) =>
+      new Promise((resolve) =>
+        pty.onExit((err, exitCode) => {
+          expect(err).toBeNull();
+          resolve(exitCode);
+        }),
+      );
+
+    // Replaces the callback from the options while the child is running...
+    expect(await exitCodeOf(spawn())).toBe(5);
+
+    // ...or fires right away once it was reaped.
+    const pty = spawn();
+    pty.onExit(() => {});
+    await pty.wait();
+    expect(await exitCodeOf(pty)).toBe(5);
+  });
+
+  test('can share a process group', (done) => {
+    let exited = 0;
+    const onExit = () => {
+      exited += 1;
+
+      if (exited === 2) {
+        done();
+      }
+    };
+
+    const leader = new Pty({
+      command: '/bin/sleep',
+      args: ['10'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit,
+      pgid: 0,
+    });
+
+    const member = new Pty({
+      command: '/bin/sleep',
+      args: ['10'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit,
+      pgid: leader.pid,
+    });
+
+    expect(leader.pgid).toBe(leader.pid);
+    expect(member.pgid).toBe(leader.pid);
+
+    process.kill(-leader.pgid, 'SIGKILL');
+  });
+
+  test('survives the controller closing when hangupOnClose is false', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'sleep 0.3; exit 7'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      hangupOnClose: false,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(7);
+        done();
+      },
+    });
+
+    setTimeout(() => fs.closeSync(pty.takeFd()), 100);
+  });
+
+  test.if(process.platform === 'linux')('reads /proc stats', (done) => {
+    const pty = new Pty({
+      command: '/bin/sleep',
+      args: ['0.2'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {
+        expect(() => pty.procStat()).toThrow('has exited');
+        done();
+      },
+    });
+
+    const stat = pty.procStat();
+    expect(stat.state).toMatch(/^[RS]$/);
+    expect(stat.rss).toBeGreaterThan(0);
+  });
+
+  test.if(process.platform === 'linux')(
+    "follows the child's working directory",
+    (done) => {
+      const pty = new Pty({
+        command: '/bin/sh',
+        args: [],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        onExit: () => {},
+      });
+
+      expect(pty.childCwd()).toBe(fs.realpathSync(CWD));
+
+      const readStream = fs.createReadStream('', { fd: pty.fd });
+      const writeStream = fs.createWriteStream('', { fd: pty.fd });
+
+      let buffer = '';
+      readStream.on('data', (chunk) => {
+        buffer += chunk.toString();
+
+        if (buffer.includes('done\r\n')) {
+          expect(pty.childCwd()).toBe('/');
+          done();
+        }
+      });
+
+      writeStream.write("cd /; echo 'done'\n");
+    },
+  );
+
+  test('prefixes reads with a control byte in packet mode', (done) => {
+    const TIOCPKT_DATA = 0;
+    const TIOCPKT_NOSTOP = 16;
+    const controlBytes: number[] = [];
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'echo hi; sleep 0.1; stty -ixon; sleep 0.1'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      packetMode: true,
+      onExit: () => {
+        expect(controlBytes).toContain(TIOCPKT_DATA);
+        expect(controlBytes).toContain(TIOCPKT_NOSTOP);
+        done();
+      },
+    });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+
+    readStream.on('data', (chunk: Buffer) => {
+      controlBytes.push(chunk[0]);
+    });
+  });
+
+  test("doesn't translate newlines with rawOutput", (done) => {
+    const message = 'hello raw';
+
+    const pty = new Pty({
+      command: '/bin/echo',
+      args: [message],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      rawOutput: true,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        done();
+      },
+    });
+
+    const readStream = fs.createReadStream('', { fd: pty.fd });
+
+    readStream.on('data', (chunk) => {
+      expect(chunk.toString()).toBe(message + '\n');
+    });
+  });
+
+  test.if(process.platform === 'linux')('can grow the output pipe', (done) => {
+    // More than the default 64KiB pipe capacity, which would block the child.
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'head -c 200000 /dev/zero'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      mode: 'pipe',
+      pipeSize: 256 * 1024,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        expect(pty.pendingOutput()).toBe(200000);
+        done();
+      },
+    });
+  });
+
+  test.if(process.platform === 'linux')('writes its pid to cgroupProcsFd', (done) => {
+    // Any writable fd will do, a real `cgroup.procs` would move the child.
+    const path = `/tmp/ruspty-cgroup-procs-${process.pid}`;
+    const fd = fs.openSync(path, 'w');
+    const opts = {
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    };
+
+    // By the time the pid is written, fd 1 is the child's own stdout.
+    expect(() => new Pty({ ...opts, cgroupProcsFd: 1 })).toThrow(
+      'cgroup_procs_fd 1 is a stdio fd',
+    );
+
+    const pty = new Pty({
+      ...opts,
+      cgroupProcsFd: fd,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        expect(fs.readFileSync(path, 'utf8')).toBe(String(pty.pid));
+
+        fs.closeSync(fd);
+        fs.unlinkSync(path);
+        done();
+      },
+    });
+  });
+
+  test.if(process.platform === 'linux')('reports the tty device', () => {
+    const pty = new Pty({
+      command: '/bin/sleep',
+      args: ['0.2'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    // `tty_nr` is the 7th field of /proc/<pid>/stat, i.e. the 5th after the command name.
+    const stat = fs.readFileSync(`/proc/${pty.pid}/stat`, 'utf8');
+    const ttyNr = Number(stat.slice(stat.lastIndexOf(')') + 2).split(' ')[4]);
+
+    expect(pty.ttyDev()).toBe(ttyNr);
+  });
+
+  test('can be reaped manually when manageWait is false', (done) => {
+    let onExitCode: number | null = null;
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'sleep 0.1; echo bye; exit 5'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      manageWait: false,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        onExitCode = exitCode;
+      },
+    });
+
+    expect(pty.tryReap()).toBeNull();
+
+    const interval = setInterval(() => {
+      const exitCode = pty.tryReap();
+
+      if (exitCode !== null) {
+        clearInterval(interval);
+        expect(exitCode).toBe(5);
+
+        // What the child wrote before exiting can still be read after reaping it.
+        const buf = Buffer.alloc(64);
+        expect(buf.subarray(0, fs.readSync(pty.fd, buf)).toString()).toBe('bye\r\n');
+
+        setTimeout(() => {
+          expect(onExitCode).toBe(5);
+          done();
+        });
+      }
+    }, 20);
+  });
+
+  test('runs prepare steps before exec', (done) => {
+    const dir = fs.mkdtempSync('/tmp/ruspty-prepare-');
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'test -d sub && test -f sub/file'],
+      envs: {},
+      dir,
+      size: { rows: 24, cols: 80 },
+      prepare: [
+        { op: 'mkdir', path: 'sub' },
+        { op: 'touch', path: 'sub/file' },
+        { op: 'chmod', path: 'sub/file', mode: 0o600 },
+      ],
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        expect(fs.statSync(`${dir}/sub/file`).mode & 0o777).toBe(0o600);
+
+        fs.rmSync(dir, { recursive: true });
+        done();
+      },
+    });
+  });
+
+  test('rejects invalid prepare steps', () => {
+    const spawn = (prepare: any) =>
+      new Pty({
+        command: '/bin/true',
+        args: [],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        prepare,
+      });
+
+    expect(() => spawn([{ op: 'rm', path: 'x' }])).toThrow('unknown op');
+    expect(() => spawn([{ op: 'chmod', path: 'x' }])).toThrow('mode is required');
+  });
+
+  test('waitClosed resolves after the output was drained', async () => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'seq 1 300'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    // Start reading only after the child is gone, its output must still be there.
+    await pty.wait();
+
+    let output = '';
+    const closed = pty.waitClosed();
+    const buf = Buffer.alloc(4096);
+    for (;;) {
+      try {
+        const n = fs.readSync(pty.fd, buf);
+        if (n === 0) break;
+        output += buf.subarray(0, n).toString();
+      } catch (e) {
+        if (e.code === 'EAGAIN') continue;
+        break;
+      }
+    }
+
+    await closed;
+    expect(output.trim().split(/\r?\n/).pop()).toBe('300');
+  });
+
+  test('refuses to use the controller fd once it was closed', async () => {
+    const pty = new Pty({
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    await pty.waitClosed();
+    // Likely to reuse the controller's fd number, which must not be touched through `pty`.
+    const file = fs.openSync(CWD + '/package.json', 'r');
+
+    const closed = 'controller fd was closed after the child exited';
+    expect(() => pty.resize({ rows: 30, cols: 100 })).toThrow(closed);
+    expect(() => pty.resetSize()).toThrow(closed);
+    expect(() => pty.resizeFromEnv({ LINES: '30' })).toThrow(closed);
+    expect(() => pty.readable()).toThrow(closed);
+    expect(() => pty.writeAll(Buffer.from('x'))).toThrow(closed);
+    expect(() => pty.borrowFd()).toThrow(closed);
+    expect(() => pty.pendingOutput()).toThrow(closed);
+    expect(() => pty.flushOutput()).toThrow(closed);
+    expect(() => pty.flushInput()).toThrow(closed);
+    expect(() => pty.getSpeed()).toThrow(closed);
+    expect(() => pty.setSpeed(9600)).toThrow(closed);
+    expect(() => pty.takeFds()).toThrow(closed);
+
+    fs.closeSync(file);
+  });
+
+  test.if(process.platform === 'linux')('can suspend and resume the child', (done) => {
+    const pty = new Pty({
+      command: '/bin/sleep',
+      args: ['1'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => done(),
+    });
+
+    pty.suspend();
+    setTimeout(() => {
+      expect(pty.procStat().state).toBe('T');
+
+      pty.resumeProcess();
+      setTimeout(() => {
+        expect(pty.procStat().state).toBe('S');
+        process.kill(pty.pid, 'SIGKILL');
+      }, 50);
+    }, 50);
+  });
+
+  test('throws errors with stable codes', () => {
+    const spawn = (opts: Partial<PtyOptions>) =>
+      new Pty({
+        command: '/bin/sleep',
+        args: ['1'],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        ...opts,
+      });
+    const codeOf = (fn: () => unknown) => {
+      try {
+        fn();
+      } catch (e) {
+        return e.code;
+      }
+    };
+
+    expect(codeOf(() => spawn({ pgid: -1 }))).toBe('ERR_PTY_INVALID_ARGUMENT');
+    expect(codeOf(() => spawn({ command: '/bin/this-does-not-exist' }))).toBe(
+      'ERR_PTY_COMMAND_NOT_FOUND',
+    );
+    expect(codeOf(() => spawn({ command: 'this-does-not-exist' }))).toBe(
+      'ERR_PTY_COMMAND_NOT_FOUND',
+    );
+    expect(codeOf(() => spawn({ dir: '/this-does-not-exist' }))).toBe(
+      'ERR_PTY_SPAWN_FAILED',
+    );
+
+    const pty = spawn({ mode: 'pipe' });
+    expect(codeOf(() => pty.ttyDev())).toBe('ERR_PTY_UNSUPPORTED');
+    expect(codeOf(() => pty.flushOutput())).toBe('ERR_PTY_UNSUPPORTED');
+    expect(codeOf(() => pty.resize({ rows: 30, cols: 100 }))).toBe(
+      'ERR_PTY_UNSUPPORTED',
+    );
+    expect(codeOf(() => pty.resetSize())).toBe('ERR_PTY_UNSUPPORTED');
+
+    fs.closeSync(pty.takeFd());
+    expect(codeOf(() => pty.borrowFd())).toBe('ERR_PTY_FD_TAKEN');
+  });
+
+  test.if(process.platform === 'linux')(
+    'closes its fds when the command is not found',
+    () => {
+      const fdCount = () => fs.readdirSync('/proc/self/fd').length;
+      const before = fdCount();
+
+      for (let i = 0; i < 10; i++) {
+        expect(
+          () =>
+            new Pty({
+              command: '/bin/this-does-not-exist',
+              args: [],
+              envs: {},
+              dir: CWD,
+              size: { rows: 24, cols: 80 },
+            }),
+        ).toThrow('command not found: /bin/this-does-not-exist');
+      }
+
+      expect(fdCount()).toBe(before);
+    },
+  );
+
+  test('can take both fds', () => {
+    const pty = new Pty({
+      command: '/bin/sleep',
+      args: ['1'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    const { controller, user } = pty.takeFds();
+    expect(pty.fdTaken()).toBe(true);
+    expect(user).toBeDefined();
+
+    // Whatever is written to the user side comes out of the controller.
+    fs.writeSync(user!, 'hello\n');
+    const buf = Buffer.alloc(64);
+    expect(buf.subarray(0, fs.readSync(controller, buf)).toString()).toBe('hello\r\n');
+
+    process.kill(pty.pid, 'SIGKILL');
+    fs.closeSync(user!);
+    fs.closeSync(controller);
+  });
+
+  test('can split stderr from stdout', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'echo out; echo err >&2'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      stderr: 'pipe',
+      splitStderr: true,
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+
+        const buf = Buffer.alloc(64);
+        expect(buf.subarray(0, fs.readSync(pty.fd, buf)).toString()).toBe('out\r\n');
+        expect(buf.subarray(0, fs.readSync(pty.stderrFd!, buf)).toString()).toBe('err\n');
+        done();
+      },
+    });
+  });
+
+  test('can configure each stdio stream', () => {
+    const opts = {
+      command: '/bin/sh',
+      args: ['-c', 'echo out; echo err >&2'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    };
+
+    expect(run({ ...opts, stdout: 'null' }).output.toString()).toBe('err\r\n');
+    expect(
+      run({ ...opts, mode: 'pipe', stdin: 'null', stderr: 'null' }).output.toString(),
+    ).toBe('out\n');
+
+    // In pty mode a stdin pipe gets its own fd.
+    const pty = new Pty({ ...opts, stdin: 'pipe', onExit: () => {} });
+    expect(pty.inputFd).not.toBe(pty.fd);
+    fs.closeSync(pty.takeFds().controller);
+
+    // An explicit stderr pipe is one of its own, which `run` doesn't read.
+    expect(() => run({ ...opts, mode: 'pipe', stderr: 'pipe' })).toThrow(
+      'stderr "pipe" is not supported by run',
+    );
+    expect(() => run({ ...opts, splitStderr: true })).toThrow(
+      'stderr "pipe" is not supported by run',
+    );
+    expect(() => run({ ...opts, stdout: 'pipe' })).toThrow(
+      'stdout "pipe" in pty mode is not supported by run',
+    );
+    expect(() => run({ ...opts, mode: 'pipe', stdin: 'pty' })).toThrow(
+      'stdin "pty" requires mode "pty"',
+    );
+    expect(() =>
+      run({ ...opts, splitStderr: true, stderr: 'null' }),
+    ).toThrow('split_stderr conflicts with stderr "null"');
+  });
+
+  test('can pipe stdout while stdin and stderr stay on the pty', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'echo out; echo err >&2; [ -t 0 ] && [ ! -t 1 ] && [ -t 2 ]'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      stdin: 'pty',
+      stdout: 'pipe',
+      stderr: 'pty',
+      onExit: (err, exitCode) => {
         expect(err).toBeNull();
         expect(exitCode).toBe(0);
+
+        const buf = Buffer.alloc(64);
+        expect(buf.subarray(0, fs.readSync(pty.stdoutFd!, buf)).toString()).toBe('out\n');
+        expect(buf.subarray(0, fs.readSync(pty.fd, buf)).toString()).toBe('err\r\n');
         done();
       },
+    });
+  });
+
+  test('caps the output collected by run', () => {
+    const { exitCode, output, truncated } = run({
+      command: '/bin/sh',
+      args: ['-c', 'yes'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      maxOutputBytes: 6,
+      killOnOutputLimit: true,
+    });
+
+    expect(exitCode).toBe(-1);
+    expect(truncated).toBe(true);
+    expect(output.toString()).toBe('y\r\ny\r\n');
+  });
+
+  test('caps the output passed to onData', (done) => {
+    const opts = {
+      command: '/bin/sh',
+      args: ['-c', 'yes'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      maxOutputBytes: 6,
+      killOnOutputLimit: true,
+    };
+
+    expect(() => new Pty(opts)).toThrow('max_output_bytes requires on_data');
+
+    let output = '';
+    new Pty({
+      ...opts,
+      onData: (data) => {
+        output += data.toString();
+      },
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(-1);
+
+        setTimeout(() => {
+          expect(output).toBe('y\r\ny\r\n');
+          done();
+        }, 100);
+      },
+    });
+  });
+
+  test('sets terminal env vars', () => {
+    const spawn = (opts: Partial<PtyOptions>) =>
+      run({
+        command: '/bin/sh',
+        args: ['-c', 'echo "$TERM $COLUMNS $LINES"'],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        ...opts,
+      }).output.toString();
+
+    expect(spawn({ term: 'vt100', sizeEnv: true })).toBe('vt100 80 24\r\n');
+    expect(spawn({ term: 'vt100', sizeEnv: true, envs: { TERM: 'dumb', LINES: '7' } })).toBe(
+      'dumb 80 7\r\n',
+    );
+  });
+
+  test('applies env pairs in order after envs', () => {
+    const { output } = run({
+      command: '/bin/sh',
+      args: ['-c', 'echo "$A $B"'],
+      envs: { A: 'envs' },
+      envPairs: ['A=first', 'B=x=y', 'A=second'],
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(output.toString()).toBe('second x=y\r\n');
+  });
+
+  test('drops unset env vars', () => {
+    // `HOME` is inherited from the parent, `A` comes from `envs`.
+    const { output } = run({
+      command: '/bin/sh',
+      args: ['-c', 'echo "[$HOME] [$A] $B"'],
+      envs: { A: 'a', B: 'b' },
+      unsetEnvs: ['HOME', 'A'],
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(output.toString()).toBe('[] [] b\r\n');
+  });
+
+  test('hands a log fd to the child', () => {
+    const dir = fs.mkdtempSync('/tmp/ruspty-log-');
+    const logFd = fs.openSync(`${dir}/log`, 'w');
+    const opts = {
+      command: '/bin/sh',
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      logFd,
+    };
+
+    expect(run({ ...opts, args: ['-c', 'echo three >&3'] }).exitCode).toBe(0);
+    expect(
+      run({ ...opts, args: ['-c', 'echo seven >&7'], logFdTarget: 7 }).exitCode,
+    ).toBe(0);
+    expect(() => run({ ...opts, args: [], logFdTarget: 1 })).toThrow(
+      'invalid log_fd_target: 1',
+    );
+    // The child's fd 1 is its pty by then, not the caller's stdout.
+    expect(() => run({ ...opts, args: [], logFd: 1 })).toThrow(
+      'log_fd 1 is a stdio fd',
     );
 
+    fs.closeSync(logFd);
+    expect(fs.readFileSync(`${dir}/log`, 'utf8')).toBe('three\nseven\n');
+    fs.rmSync(dir, { recursive: true });
+  });
+
+  test('feeds stdinData followed by EOF', () => {
+    const { exitCode, output } = run({
+      command: '/bin/sh',
+      args: ['-c', 'cat; test -t 0 || echo piped; test -t 1 && echo tty'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      stdinData: Buffer.from('hello\n'),
+    });
+
+    expect(exitCode).toBe(0);
+    expect(output.toString()).toBe('hello\r\npiped\r\ntty\r\n');
+  });
+
+  test('tees output to a log file', (done) => {
+    const dir = fs.mkdtempSync('/tmp/ruspty-output-log-');
+    const opts = {
+      command: '/bin/echo',
+      args: ['logged'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      outputLogPath: `${dir}/output.log`,
+    };
+
+    expect(run(opts).output.toString()).toBe('logged\r\n');
+    expect(() => new Pty(opts)).toThrow('output_log_path requires on_data');
+    expect(() =>
+      run({ ...opts, outputLogPath: `${dir}/missing/output.log` }),
+    ).toThrow('failed to open output log');
+
+    new Pty({
+      ...opts,
+      onData: (data) => {
+        expect(data.toString()).toBe('logged\r\n');
+        expect(fs.readFileSync(`${dir}/output.log`, 'utf8')).toBe(
+          'logged\r\nlogged\r\n',
+        );
+
+        fs.rmSync(dir, { recursive: true });
+        done();
+      },
+    });
+  });
+
+  test.if(process.platform === 'linux')('can set a custom argv[0]', () => {
+    const { output } = run({
+      command: '/bin/cat',
+      args: ['/proc/self/cmdline'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      argv0: '-custom',
+    });
+
+    expect(output.toString()).toBe('-custom\0/proc/self/cmdline\0');
+  });
+
+  test('can run a command to completion', () => {
+    const { exitCode, output } = run({
+      command: '/bin/sh',
+      args: ['-c', 'seq 1 10000; exit 3'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(exitCode).toBe(3);
+    expect(output.toString().trim().split(/\r?\n/).length).toBe(10000);
+  });
+
+  test('counts live ptys', () => {
+    const before = livePtyCount();
+
+    const pty = new Pty({
+      command: '/bin/true',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(livePtyCount()).toBe(before + 1);
+    expect(pty.pid).toBeGreaterThan(0);
+  });
+
+  test.if(process.platform === 'linux')("doesn't leak fds once collected", async () => {
+    const countFds = () => fs.readdirSync('/proc/self/fd').length;
+    const fdsBefore = countFds();
+    const liveBefore = livePtyCount();
+
+    await (async () => {
+      const pty = new Pty({
+        command: '/bin/true',
+        args: [],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+      });
+      await pty.waitClosed();
+    })();
+
+    Bun.gc(true);
+    await new Promise((resolve) => setTimeout(resolve, 100));
+    Bun.gc(true);
+
+    expect(livePtyCount()).toBe(liveBefore);
+    expect(countFds()).toBe(fdsBefore);
+  });
+
+  test('collects ptys whose callbacks refer to them', async () => {
+    const liveBefore = livePtyCount();
+
+    for (let i = 0; i < 5; i++) {
+      await new Promise<void>((resolve) => {
+        const pty = new Pty({
+          command: '/bin/echo',
+          args: ['hi'],
+          envs: {},
+          dir: CWD,
+          size: { rows: 24, cols: 80 },
+          onData: () => expect(pty.pid).toBeGreaterThan(0),
+          onExit: () => {
+            expect(pty.pid).toBeGreaterThan(0);
+            resolve();
+          },
+        });
+      });
+    }
+
+    // Give the reader threads a moment to finish with `onData`.
+    await new Promise((resolve) => setTimeout(resolve, 200));
+    Bun.gc(true);
+    await new Promise((resolve) => setTimeout(resolve, 100));
+    Bun.gc(true);
+
+    expect(livePtyCount()).toBe(liveBefore);
+  });
+
+  test('reports whether the child leads its session', () => {
+    const spawn = (pgid?: number) =>
+      new Pty({
+        command: '/bin/sleep',
+        args: ['1'],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        pgid,
+      });
+
+    const leader = spawn();
+    const member = spawn(0);
+
+    expect(leader.isSessionLeader()).toBe(true);
+    expect(member.isSessionLeader()).toBe(false);
+
+    process.kill(leader.pid, 'SIGKILL');
+    process.kill(member.pid, 'SIGKILL');
+  });
+
+  test('exposes what it was spawned with', () => {
+    const pty = new Pty({
+      command: '/bin/echo',
+      args: ['hello', 'world'],
+      envs: {},
+      dir: '.',
+      size: { rows: 24, cols: 80 },
+    });
+
+    expect(pty.command).toBe('/bin/echo');
+    expect(pty.args).toStrictEqual(['hello', 'world']);
+    expect(pty.dir).toBe(fs.realpathSync('.'));
+  });
+
+  test('can be respawned with the same options', (done) => {
+    const exitCodes: number[] = [];
+    let respawned: Pty | null = null;
+
+    const onExit = (err: null | Error, exitCode: number) => {
+      expect(err).toBeNull();
+      exitCodes.push(exitCode);
+
+      if (exitCodes.length === 1) {
+        respawned = pty.respawn(onExit);
+        expect(respawned.pid).not.toBe(pty.pid);
+      } else {
+        expect(exitCodes).toStrictEqual([3, 3]);
+        done();
+      }
+    };
+
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'exit 3'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit,
+    });
+  });
+
+  test('reports when the controller fd was taken', (done) => {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'sleep 0.1'],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+
+        // The fd belongs to us now, so it must still be open after the child exited.
+        fs.fstatSync(fd);
+        fs.closeSync(fd);
+        done();
+      },
+    });
+
+    expect(pty.fdTaken()).toBe(false);
+    const fd = pty.takeFd();
+    expect(pty.fdTaken()).toBe(true);
+    expect(() => pty.resize({ rows: 30, cols: 100 })).toThrow('controller fd was taken');
+    expect(() => pty.takeFd()).toThrow('controller fd was taken');
+  });
+
+  test('respects working directory', (done) => {
+    const pty = new Pty({
+      command: '/bin/pwd',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
+        expect(err).toBeNull();
+        expect(exitCode).toBe(0);
+        done();
+      },
+    });
+
     const readStream = fs.createReadStream('', { fd: pty.fd });
 
     readStream.on('data', (chunk) => {
@@ -124,22 +1461,22 @@ describe('PTY', () => {
     const message = 'hello from env';
     let buffer = '';
 
-    const pty = new Pty(
-      '/bin/sh',
-      ['-c', 'sleep 0.1s && echo $ENV_VARIABLE && exit'],
-      {
+    const pty = new Pty({
+      command: '/bin/sh',
+      args: ['-c', 'sleep 0.1s && echo $ENV_VARIABLE && exit'],
+      envs: {
         ENV_VARIABLE: message,
       },
-      CWD,
-      { rows: 24, cols: 80 },
-      (err, exitCode) => {
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: (err, exitCode) => {
         expect(err).toBeNull();
         expect(exitCode).toBe(0);
         expect(buffer).toBe(message + '\r\n');
 
         done();
       },
-    );
+    });
 
     const readStream = fs.createReadStream('', { fd: pty.fd });
 
@@ -151,14 +1488,14 @@ describe('PTY', () => {
   test('works with Bun.read & Bun.write', (done) => {
     const message = 'hello bun';
 
-    const pty = new Pty(
-      '/bin/cat',
-      [],
-      {},
-      CWD,
-      { rows: 24, cols: 80 },
-      () => {},
-    );
+    const pty = new Pty({
+      command: '/bin/cat',
+      args: [],
+      envs: {},
+      dir: CWD,
+      size: { rows: 24, cols: 80 },
+      onExit: () => {},
+    });
 
     const file = Bun.file(pty.fd);
 
@@ -178,16 +1515,19 @@ describe('PTY', () => {
 
   test("doesn't break when executing non-existing binary", (done) => {
     try {
-      new Pty(
-        '/bin/this-does-not-exist',
-        [],
-        {},
-        CWD,
-        { rows: 24, cols: 80 },
-        () => {},
-      );
+      new Pty({
+        command: '/bin/this-does-not-exist',
+        args: [],
+        envs: {},
+        dir: CWD,
+        size: { rows: 24, cols: 80 },
+        onExit: () => {},
+      });
     } catch (e) {
-      expect(e.message).toContain('No such file or directory');
+      expect(e.code).toBe('ERR_PTY_COMMAND_NOT_FOUND');
+      expect(e.message).toContain(
+        'command not found: /bin/this-does-not-exist',
+      );
 
       done();
     }
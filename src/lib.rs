@@ -1,223 +1,2735 @@
 use libc::{self, c_int, TIOCSCTTY};
-use napi::bindgen_prelude::JsFunction;
+use napi::bindgen_prelude::{AsyncTask, Buffer, JsFunction};
 use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
 use napi::Error as NAPI_ERROR;
 use napi::Status::GenericFailure;
+use napi::{Env, JsObject, Task};
 use rustix_openpty::openpty;
+use rustix_openpty::rustix::pty::ptsname;
 use rustix_openpty::rustix::termios::Winsize;
-use rustix_openpty::rustix::termios::{self, InputModes, OptionalActions};
+use rustix_openpty::rustix::termios::{
+  self, InputModes, OptionalActions, OutputModes, QueueSelector,
+};
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::fs::File;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::io::Write;
+use std::os::fd::AsFd;
 use std::os::fd::AsRawFd;
-use std::os::fd::FromRawFd;
-use std::os::unix::process::CommandExt;
-use std::process::{Command, Stdio};
+use std::os::fd::OwnedFd;
+use std::os::fd::{BorrowedFd, IntoRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate napi_derive;
 
+type ExitCallback = ThreadsafeFunction<i32, ErrorStrategy::CalleeHandled>;
+type DataCallback = ThreadsafeFunction<Vec<u8>, ErrorStrategy::Fatal>;
+type PtyError = NAPI_ERROR<ErrorCode>;
+
+/// Stable codes for the errors thrown by `Pty`, exposed as their `code` property so consumers can
+/// branch on the kind of failure instead of matching messages. Promise rejections (`readable`,
+/// `write_all`, `wait`, `terminate`) still use napi's `GenericFailure`, as napi can't attach
+/// custom codes to those.
+#[derive(Clone, Copy, Debug)]
+enum ErrorCode {
+  /// An option or argument was rejected before anything was attempted.
+  InvalidArgument,
+  /// Setting up the child's stdio or spawning the child failed.
+  SpawnFailed,
+  /// The command to spawn doesn't exist (or isn't on `PATH`).
+  CommandNotFound,
+  /// The controller fd was handed over with `take_fd`.
+  FdTaken,
+  /// The operation isn't available on this platform or with these options.
+  Unsupported,
+  /// The operation needs a child that is still running.
+  ChildExited,
+  /// A system call on the pty or the child failed.
+  Io,
+  /// An error from napi itself, keeping its original status.
+  Napi(napi::Status),
+}
+
+impl AsRef<str> for ErrorCode {
+  fn as_ref(&self) -> &str {
+    match self {
+      ErrorCode::InvalidArgument => "ERR_PTY_INVALID_ARGUMENT",
+      ErrorCode::SpawnFailed => "ERR_PTY_SPAWN_FAILED",
+      ErrorCode::CommandNotFound => "ERR_PTY_COMMAND_NOT_FOUND",
+      ErrorCode::FdTaken => "ERR_PTY_FD_TAKEN",
+      ErrorCode::Unsupported => "ERR_PTY_UNSUPPORTED",
+      ErrorCode::ChildExited => "ERR_PTY_CHILD_EXITED",
+      ErrorCode::Io => "ERR_PTY_IO",
+      ErrorCode::Napi(status) => status.as_ref(),
+    }
+  }
+}
+
+fn napi_error(err: NAPI_ERROR) -> PtyError {
+  PtyError::new(ErrorCode::Napi(err.status), err.reason)
+}
+
+// Dropping a `Pty` (i.e. it being garbage collected) closes the fds it still owns: the input,
+// stdout and stderr pipes right away, and the controller once nobody else holds on to it. Unless
+// it was handed over with `take_fd`, the wait thread keeps the controller open until the child
+// exited and its output was drained; the thread intentionally outlives the `Pty` so that `on_exit`
+// still fires. With `manage_wait: false` the child is left alone, reaping it stays the embedder's
+// job.
 #[napi]
 #[allow(dead_code)]
 struct Pty {
-  file: File,
+  controller: Arc<Controller>,
+  fd_taken: bool,
   #[napi(ts_type = "number")]
   pub fd: c_int,
+  // Separate stdin pipe in pipe mode (or with `stdin: "pipe"`), in pty mode input goes through the
+  // controller as well.
+  input: Option<File>,
+  /// Fd to write the child's input to. Same as `fd` in pty mode, unless stdin is a pipe.
+  #[napi(ts_type = "number")]
+  pub input_fd: c_int,
+  // Read end of the child's stdout with `stdout: "pipe"` in pty mode.
+  stdout: Option<File>,
+  /// Fd to read the child's stdout from, only set with `stdout: "pipe"` in pty mode (in pipe mode
+  /// that is `fd`).
+  pub stdout_fd: Option<i32>,
+  // Read end of the child's stderr with `stderr: "pipe"`.
+  stderr: Option<File>,
+  /// Fd to read the child's stderr from, only set with `stderr: "pipe"` (or `split_stderr`).
+  pub stderr_fd: Option<i32>,
   pub pid: u32,
+  pub pgid: i32,
+  tty_dev: Option<libc::dev_t>,
+  manage_wait: bool,
+  // Only populated when `manage_wait` is false, otherwise the wait thread owns it.
+  child: Option<Child>,
+  exit_state: Arc<ExitState>,
+  _live: LivePty,
+  // What the child was spawned with, kept around for `respawn`, without `on_exit` and `on_data`.
+  options: PtyOptions,
+  // `options.dir` made absolute at spawn time.
+  dir: String,
+  // Whether `on_data` reads the controller fd, which then can't be taken.
+  forwards_output: bool,
+}
+
+#[napi(object)]
+struct PtyOptions {
+  pub command: String,
+  pub args: Vec<String>,
+  pub envs: HashMap<String, String>,
+  pub dir: String,
+  pub size: Size,
+  #[napi(ts_type = "(err: null | Error, exitCode: number) => void")]
+  pub on_exit: Option<JsFunction>,
+  /// Process group to place the child in. When unset, the child becomes the leader of a new
+  /// session (and process group) with the pty as its controlling terminal. `0` creates a new
+  /// group led by the child, any other value joins that existing group; both skip `setsid`, so
+  /// the child stays in the caller's session and the pty is *not* its controlling terminal.
+  pub pgid: Option<i32>,
+  /// Whether closing the controller fd should hang up the child (the kernel sends `SIGHUP` to
+  /// the session of a pty's controlling terminal). Defaults to `true`. When `false`, the child
+  /// still gets its own session but the pty never becomes its controlling terminal, so it
+  /// survives the controller being closed. The tradeoff is that the child has no job control:
+  /// typing `^C`/`^Z` doesn't signal it and opening `/dev/tty` fails.
+  pub hangup_on_close: Option<bool>,
+  /// Enables packet mode (`TIOCPKT`) on the controller. In packet mode every read from the
+  /// controller fd is prefixed with a control byte: `0` (`TIOCPKT_DATA`) followed by regular
+  /// output, or a bitmask of `TIOCPKT_*` flags reporting flush and flow-control events on the
+  /// child's side, with no data following it. Consumers must strip/interpret that byte.
+  pub packet_mode: Option<bool>,
+  /// Disables output post-processing (`OPOST`), so the child's `\n` isn't translated to `\r\n`.
+  /// Useful when capturing output for storage rather than rendering it in a terminal emulator.
+  /// Programs that reset the termios themselves (e.g. `stty sane`) re-enable it.
+  pub raw_output: Option<bool>,
+  /// Whether to reap the child on a dedicated wait thread (the default). Embedders with their
+  /// own `SIGCHLD` handling can set this to `false` to avoid double-waiting, and drive reaping
+  /// themselves through `try_reap()`, which also fires `on_exit`. Nothing else reaps the child
+  /// then, not even once the `Pty` was garbage collected.
+  pub manage_wait: Option<bool>,
+  /// How to wire the child's stdio: `"pty"` (the default) or `"pipe"`. In pipe mode stdout and
+  /// stderr go to a pipe whose read end is `fd`, stdin comes from a pipe whose write end is
+  /// `input_fd`, and the child gets a new session without a controlling terminal. Terminal-only
+  /// features (`resize`, `packet_mode`, `raw_output`, ...) aren't available in pipe mode.
+  #[napi(ts_type = "'pty' | 'pipe'")]
+  pub mode: Option<String>,
+  /// Filesystem setup to run in the child right before `exec`, in order, e.g. to create a
+  /// directory the command expects. Relative paths resolve against `dir`, which has to exist
+  /// already. The first step that fails aborts the spawn with its error.
+  pub prepare: Option<Vec<PrepareStep>>,
+  /// An fd open for writing on a cgroup (v2) `cgroup.procs` file, which the child writes its own
+  /// pid into before `exec`. Unlike `move_to_cgroup`, this needs no access to the cgroup's path
+  /// and leaves no window where the child runs outside of it. The fd stays owned by the caller,
+  /// the command itself doesn't inherit it. Can't be one of stdin, stdout or stderr. Linux only.
+  pub cgroup_procs_fd: Option<i32>,
+  /// Routes the child's stderr to a separate pipe whose read end is `stderr_fd`, instead of
+  /// merging it with stdout. In pty mode stdout stays on the pty, so the child still sees a
+  /// terminal there, but stderr no longer is one (e.g. `isatty(2)` is false). Shorthand for
+  /// `stderr: "pipe"`, so it can't be combined with any other `stderr`.
+  pub split_stderr: Option<bool>,
+  /// Requested capacity of the output pipe in bytes (`F_SETPIPE_SZ`), e.g. to cut down on
+  /// wakeups when capturing lots of output. The kernel rounds it up to a power-of-two number of
+  /// pages, and unprivileged processes can't go beyond `/proc/sys/fs/pipe-max-size`. Only
+  /// supported in pipe mode (a pty's buffer size is fixed) and on Linux.
+  pub pipe_size: Option<u32>,
+  /// Value of `TERM` for the child. In pty mode it defaults to `xterm-256color` when the parent
+  /// has no `TERM` to pass down, so that curses programs work out of the box. An entry in `envs`
+  /// takes precedence.
+  pub term: Option<String>,
+  /// Whether to set `COLUMNS` and `LINES` in the child's environment from the initial `size`,
+  /// for programs that don't query the terminal size. Entries in `envs` take precedence.
+  pub size_env: Option<bool>,
+  /// Extra environment variables as raw `KEY=VALUE` entries, like `environ`, applied in order
+  /// after `envs` (so they take precedence over it, and later entries over earlier ones).
+  pub env_pairs: Option<Vec<String>>,
+  /// Variables to remove from the child's environment, e.g. secrets the parent has but the child
+  /// shouldn't inherit. Applied last, so this also drops entries from `envs` and `env_pairs`.
+  pub unset_envs: Option<Vec<String>>,
+  /// Caps how much output is read natively: what `run()` collects, or what `on_data` is called
+  /// with. Once reached, the rest is read and discarded (or the child is killed, with
+  /// `kill_on_output_limit`), and `run()` marks its result as truncated. Without `on_data`, a
+  /// `new Pty(...)` leaves reading `fd` to the consumer, so it rejects this.
+  pub max_output_bytes: Option<i64>,
+  /// Whether to kill the child's process group (`SIGKILL`) once `max_output_bytes` is reached,
+  /// instead of letting it finish.
+  pub kill_on_output_limit: Option<bool>,
+  /// A cgroup (v2) directory whose accounting is read as soon as the child is reaped and
+  /// reported in `ExitInfo` (`memory.peak`, `cpu.stat`), before a supervisor gets to tear the
+  /// cgroup down. This doesn't put the child there, combine it with `cgroup_procs_fd` or
+  /// `move_to_cgroup`. Stats that can't be read are left unset. Linux only.
+  pub cgroup_path: Option<String>,
+  /// An fd open for writing, e.g. a log file, to hand to the child as fd `log_fd_target`, for
+  /// commands that expect to find one there. The child gets its own duplicate without
+  /// `FD_CLOEXEC`, the fd itself stays owned by the caller. Can't be one of stdin, stdout or
+  /// stderr, which are already the child's own by then.
+  pub log_fd: Option<i32>,
+  /// Fd number the child gets `log_fd` as. Defaults to `3`, stdio (`0`-`2`) can't be replaced.
+  pub log_fd_target: Option<i32>,
+  /// Called with every chunk of output read from the controller (the pty, or the output pipe in
+  /// pipe mode), for consumers that would rather not read `fd` themselves. A native thread does
+  /// the reading and stops while chunks are waiting to be delivered, so a busy event loop slows
+  /// down the child instead of buffering without bound. Reading `fd` directly as well steals
+  /// output from the callback, and the fd can't be taken with `take_fd`. Not supported by `run()`.
+  #[napi(ts_type = "(data: Buffer) => void")]
+  pub on_data: Option<JsFunction>,
+  /// Fixed input for the child: its stdin becomes a pipe that yields this data followed by EOF,
+  /// for commands that read until the end of their input. In pty mode stdout (and stderr) stay on
+  /// the pty, so the child still formats its output for a terminal, but stdin is no longer one
+  /// (`isatty(0)` is false). Writes to `input_fd` then only reach the terminal (e.g. programs
+  /// reading `/dev/tty`) in pty mode, and fail in pipe mode.
+  pub stdin_data: Option<Buffer>,
+  /// File to append a copy of all output to, as it is read natively by `on_data` or `run()`
+  /// (which one of those is required). Created if it doesn't exist.
+  pub output_log_path: Option<String>,
+  /// Enables `push_input`. Off by default because `TIOCSTI` is a well known privilege escalation
+  /// vector: anything that can push input into a terminal can run commands as whoever reads from
+  /// it. Only turn this on for trusted local automation.
+  pub allow_push_input: Option<bool>,
+  /// Where the child's stdin comes from: `"pty"`, `"pipe"` (written to through `input_fd`),
+  /// `"null"` (`/dev/null`) or `"inherit"` (this process' stdin). Defaults to `mode`. In pty mode
+  /// a pipe takes the terminal's place on stdin only, the child can still open `/dev/tty`.
+  #[napi(ts_type = "'pty' | 'pipe' | 'null' | 'inherit'")]
+  pub stdin: Option<String>,
+  /// Where the child's stdout goes, like `stdin`. `"pipe"` is the output pipe read through `fd` in
+  /// pipe mode. In pty mode it's a pipe of its own read through `stdout_fd`, and stdout is no
+  /// longer a terminal while stdin and stderr can still be.
+  #[napi(ts_type = "'pty' | 'pipe' | 'null' | 'inherit'")]
+  pub stdout: Option<String>,
+  /// Where the child's stderr goes, like `stdout`. `"pipe"` is a pipe of its own read through
+  /// `stderr_fd` in either mode. Defaults to the pty in pty mode and to the output pipe behind `fd`
+  /// (shared with stdout) in pipe mode.
+  #[napi(ts_type = "'pty' | 'pipe' | 'null' | 'inherit'")]
+  pub stderr: Option<String>,
+  /// `argv[0]` for the child instead of `command`, for programs that look at the name they were
+  /// invoked as, e.g. `-bash` to start a login shell or an applet name for busybox.
+  pub argv0: Option<String>,
+}
+
+// `on_exit` and `on_data` are JS values that only live as long as the call they were passed to,
+// so clones leave them unset.
+impl Clone for PtyOptions {
+  fn clone(&self) -> Self {
+    PtyOptions {
+      command: self.command.clone(),
+      args: self.args.clone(),
+      envs: self.envs.clone(),
+      dir: self.dir.clone(),
+      size: self.size.clone(),
+      on_exit: None,
+      pgid: self.pgid,
+      hangup_on_close: self.hangup_on_close,
+      packet_mode: self.packet_mode,
+      raw_output: self.raw_output,
+      manage_wait: self.manage_wait,
+      mode: self.mode.clone(),
+      prepare: self.prepare.clone(),
+      cgroup_procs_fd: self.cgroup_procs_fd,
+      split_stderr: self.split_stderr,
+      pipe_size: self.pipe_size,
+      term: self.term.clone(),
+      size_env: self.size_env,
+      env_pairs: self.env_pairs.clone(),
+      unset_envs: self.unset_envs.clone(),
+      max_output_bytes: self.max_output_bytes,
+      kill_on_output_limit: self.kill_on_output_limit,
+      cgroup_path: self.cgroup_path.clone(),
+      log_fd: self.log_fd,
+      log_fd_target: self.log_fd_target,
+      on_data: None,
+      stdin_data: self.stdin_data.clone(),
+      output_log_path: self.output_log_path.clone(),
+      allow_push_input: self.allow_push_input,
+      stdin: self.stdin.clone(),
+      stdout: self.stdout.clone(),
+      stderr: self.stderr.clone(),
+      argv0: self.argv0.clone(),
+    }
+  }
+}
+
+/// A single `PtyOptions::prepare` step.
+#[napi(object)]
+#[derive(Clone)]
+struct PrepareStep {
+  /// `"mkdir"` creates a directory (succeeding if it already exists), `"touch"` creates an empty
+  /// file if it doesn't exist, `"chmod"` changes the permissions of an existing path.
+  #[napi(ts_type = "'mkdir' | 'touch' | 'chmod'")]
+  pub op: String,
+  pub path: String,
+  /// Permission bits. Required for `"chmod"`, defaults to `0o755` for `"mkdir"` and `0o644` for
+  /// `"touch"` (both subject to the umask).
+  pub mode: Option<u32>,
 }
 
 #[napi(object)]
+#[derive(Clone)]
 struct Size {
   pub cols: u16,
   pub rows: u16,
 }
 
-#[allow(dead_code)]
-fn set_controlling_terminal(fd: c_int) -> Result<(), Error> {
-  let res = unsafe {
-    #[allow(clippy::cast_lossless)]
-    libc::ioctl(fd, TIOCSCTTY as _, 0)
-  };
+/// How the child exited.
+#[napi(object)]
+#[derive(Clone)]
+struct ExitInfo {
+  /// Exit code if the child exited normally, unset if it was killed by a signal.
+  pub exit_code: Option<i32>,
+  /// Signal that killed the child, unset if it exited normally.
+  pub signal: Option<i32>,
+  pub core_dumped: bool,
+  /// Peak memory usage of `cgroup_path` in bytes (`memory.peak`).
+  pub memory_peak: Option<i64>,
+  /// Total, user and system CPU time of `cgroup_path` in microseconds (`cpu.stat`).
+  pub cpu_usage_usec: Option<i64>,
+  pub cpu_user_usec: Option<i64>,
+  pub cpu_system_usec: Option<i64>,
+}
 
-  if res != 0 {
-    return Err(Error::last_os_error());
+impl From<ExitStatus> for ExitInfo {
+  fn from(status: ExitStatus) -> Self {
+    ExitInfo {
+      exit_code: status.code(),
+      signal: status.signal(),
+      core_dumped: status.core_dumped(),
+      memory_peak: None,
+      cpu_usage_usec: None,
+      cpu_user_usec: None,
+      cpu_system_usec: None,
+    }
   }
+}
 
-  Ok(())
+impl ExitInfo {
+  /// Fills in the accounting of the cgroup at `cgroup_path`, leaving whatever can't be read
+  /// (missing controller, cgroup already removed, ...) unset.
+  #[cfg(target_os = "linux")]
+  fn read_cgroup_stats(&mut self, cgroup_path: &str) {
+    let dir = std::path::Path::new(cgroup_path);
+
+    if let Ok(peak) = std::fs::read_to_string(dir.join("memory.peak")) {
+      self.memory_peak = peak.trim().parse().ok();
+    }
+
+    if let Ok(stat) = std::fs::read_to_string(dir.join("cpu.stat")) {
+      for line in stat.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+          continue;
+        };
+        let value = value.trim().parse().ok();
+        match key {
+          "usage_usec" => self.cpu_usage_usec = value,
+          "user_usec" => self.cpu_user_usec = value,
+          "system_usec" => self.cpu_system_usec = value,
+          _ => {}
+        }
+      }
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  fn read_cgroup_stats(&mut self, _cgroup_path: &str) {}
 }
 
-#[allow(dead_code)]
-fn set_nonblocking(fd: c_int) -> Result<(), NAPI_ERROR> {
-  use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
+/// What `run` returns.
+#[napi(object)]
+struct RunResult {
+  /// Exit code of the child, `-1` if it was killed by a signal (like `on_exit`).
+  pub exit_code: i32,
+  /// Everything the child wrote to the pty (or to the output pipe in pipe mode), up to
+  /// `max_output_bytes`.
+  pub output: Buffer,
+  /// Whether output was dropped because of `max_output_bytes`.
+  pub truncated: bool,
+}
 
-  let status_flags = unsafe { fcntl(fd, F_GETFL, 0) };
+/// The child's exit status, shared between the `Pty` and whoever reaps the child.
+#[derive(Default)]
+struct ExitState {
+  status: Mutex<Option<Result<ExitInfo, NAPI_ERROR>>>,
+  reaped: Condvar,
+  // Fired (and cleared) by whichever comes second: the child being reaped, or it being set.
+  on_exit: Mutex<Option<ExitCallback>>,
+}
 
-  if status_flags < 0 {
-    return Err(NAPI_ERROR::new(
-      napi::Status::GenericFailure,
-      format!("fcntl F_GETFL failed: {}", Error::last_os_error()),
-    ));
+impl ExitState {
+  fn set(&self, status: Result<ExitInfo, NAPI_ERROR>) {
+    *self.status.lock().unwrap() = Some(status);
+    self.reaped.notify_all();
   }
 
-  let res = unsafe { fcntl(fd, F_SETFL, status_flags | O_NONBLOCK) };
+  fn get(&self) -> Option<Result<ExitInfo, NAPI_ERROR>> {
+    self.status.lock().unwrap().clone()
+  }
 
-  if res != 0 {
-    return Err(NAPI_ERROR::new(
-      napi::Status::GenericFailure,
-      format!("fcntl F_SETFL failed: {}", Error::last_os_error()),
-    ));
+  /// Sets (or replaces) the callback fired once the child is reaped, firing it right away if that
+  /// already happened.
+  fn set_on_exit(&self, on_exit: ExitCallback) {
+    *self.on_exit.lock().unwrap() = Some(on_exit);
+
+    if let Some(status) = self.get() {
+      self.fire_on_exit(&status);
+    }
   }
 
-  Ok(())
-}
+  fn fire_on_exit(&self, status: &Result<ExitInfo, NAPI_ERROR>) {
+    // Without an `on_exit` callback the child is still reaped, there's just nobody to tell.
+    let on_exit = self.on_exit.lock().unwrap().take();
+    if let Some(on_exit) = on_exit {
+      on_exit.call(
+        status.clone().map(|info| info.exit_code.unwrap_or(-1)),
+        ThreadsafeFunctionCallMode::Blocking,
+      );
+    }
+  }
 
-#[napi]
-impl Pty {
-  #[napi(constructor)]
-  #[allow(dead_code)]
-  pub fn new(
-    command: String,
-    args: Vec<String>,
-    envs: HashMap<String, String>,
-    dir: String,
-    size: Size,
-    #[napi(ts_arg_type = "(err: null | Error, exitCode: number) => void")] on_exit: JsFunction,
-  ) -> Result<Self, NAPI_ERROR> {
-    let window_size = Winsize {
-      ws_col: size.cols,
-      ws_row: size.rows,
-      ws_xpixel: 0,
-      ws_ypixel: 0,
-    };
+  fn wait(&self) -> Result<ExitInfo, NAPI_ERROR> {
+    let status = self
+      .reaped
+      .wait_while(self.status.lock().unwrap(), |status| status.is_none())
+      .unwrap();
 
-    let mut cmd = Command::new(command);
-    cmd.args(args);
+    status.clone().expect("exit status is set once reaped")
+  }
+
+  fn wait_timeout(&self, timeout: Duration) -> Option<Result<ExitInfo, NAPI_ERROR>> {
+    let (status, _) = self
+      .reaped
+      .wait_timeout_while(self.status.lock().unwrap(), timeout, |status| {
+        status.is_none()
+      })
+      .unwrap();
+
+    status.clone()
+  }
+}
 
-    let pty_pair = openpty(None, Some(&window_size))
-      .map_err(|err| NAPI_ERROR::new(napi::Status::GenericFailure, err))?;
+/// Fds handed over by `Pty::take_fds`.
+#[napi(object)]
+struct TakenFds {
+  pub controller: i32,
+  /// The pty's user side, unset in pipe mode.
+  pub user: Option<i32>,
+}
 
-    let fd_controller = pty_pair.controller.as_raw_fd();
-    let fd_user = pty_pair.user.as_raw_fd();
+/// The controller fd, shared with whoever reaps the child. It is closed once the child exited
+/// (and, with the wait thread, its output was drained), unless ownership was handed over through
+/// `take_fd`.
+struct Controller {
+  file: Mutex<Option<File>>,
+  released: Condvar,
+}
 
-    if let Ok(mut termios) = termios::tcgetattr(&pty_pair.controller) {
-      termios.input_modes.set(InputModes::IUTF8, true);
-      termios::tcsetattr(&pty_pair.controller, OptionalActions::Now, &termios)
-        .map_err(|err| NAPI_ERROR::new(napi::Status::GenericFailure, err))?;
+impl Controller {
+  fn new(file: File) -> Self {
+    Controller {
+      file: Mutex::new(Some(file)),
+      released: Condvar::new(),
     }
+  }
 
-    cmd.stdin(unsafe { Stdio::from_raw_fd(fd_user) });
-    cmd.stderr(unsafe { Stdio::from_raw_fd(fd_user) });
-    cmd.stdout(unsafe { Stdio::from_raw_fd(fd_user) });
+  /// Gives up the fd, either to close it or to hand it over.
+  fn release(&self) -> Option<File> {
+    let file = self.file.lock().unwrap().take();
+    self.released.notify_all();
+    file
+  }
 
-    cmd.envs(envs);
-    cmd.current_dir(dir);
+  /// Runs `f` on the fd unless it was released, holding on to it so it can't be closed meanwhile.
+  fn with_fd<T>(&self, f: impl FnOnce(BorrowedFd) -> T) -> Option<T> {
+    self
+      .file
+      .lock()
+      .unwrap()
+      .as_ref()
+      .map(|file| f(file.as_fd()))
+  }
 
-    unsafe {
-      cmd.pre_exec(move || {
-        let err = libc::setsid();
-        if err == -1 {
-          return Err(Error::new(ErrorKind::Other, "Failed to set session id"));
+  fn is_released(&self) -> bool {
+    self.file.lock().unwrap().is_none()
+  }
+
+  fn wait_released(&self) {
+    drop(
+      self
+        .released
+        .wait_while(self.file.lock().unwrap(), |file| file.is_some())
+        .unwrap(),
+    );
+  }
+
+  /// Waits for up to `timeout` until the child's side is closed and all of its output was read,
+  /// so that closing the fd doesn't throw away output nobody got to see yet.
+  fn wait_drained(&self, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+      {
+        let file = self.file.lock().unwrap();
+        let Some(file) = file.as_ref() else {
+          return;
+        };
+
+        let mut poll_fd = libc::pollfd {
+          fd: file.as_raw_fd(),
+          events: libc::POLLIN,
+          revents: 0,
+        };
+        let mut pending: c_int = 0;
+        let hung_up = unsafe { libc::poll(&mut poll_fd, 1, 0) } > 0
+          && poll_fd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0;
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FIONREAD, &mut pending as *mut _) };
+
+        if hung_up && (res != 0 || pending == 0) {
+          return;
         }
+      }
+
+      thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+  }
+}
 
-        set_controlling_terminal(fd_user)?;
+/// How long the wait thread keeps the controller open after the child exited, waiting for its
+/// remaining output to be read. Bounds the wait when nobody reads, or when a grandchild keeps the
+/// child's side open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How many chunks of output can wait for `on_data` before the reader thread stops reading.
+const MAX_QUEUED_CHUNKS: usize = 16;
+/// How often an idle reader thread checks whether the controller was closed without its side
+/// hanging up (e.g. a grandchild still holds it open).
+const READER_POLL_TIMEOUT_MS: c_int = 100;
 
-        libc::close(fd_user);
-        libc::close(fd_controller);
+static LIVE_PTYS: AtomicU32 = AtomicU32::new(0);
 
-        libc::signal(libc::SIGCHLD, libc::SIG_DFL);
-        libc::signal(libc::SIGHUP, libc::SIG_DFL);
-        libc::signal(libc::SIGINT, libc::SIG_DFL);
-        libc::signal(libc::SIGQUIT, libc::SIG_DFL);
-        libc::signal(libc::SIGTERM, libc::SIG_DFL);
-        libc::signal(libc::SIGALRM, libc::SIG_DFL);
+/// Counts towards `live_pty_count()` for as long as the `Pty` holding it is alive.
+struct LivePty;
 
-        Ok(())
-      });
+impl LivePty {
+  fn new() -> Self {
+    LIVE_PTYS.fetch_add(1, Ordering::Relaxed);
+    LivePty
+  }
+}
+
+impl Drop for LivePty {
+  fn drop(&mut self) {
+    LIVE_PTYS.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// A snapshot of the child's `/proc/<pid>/stat`.
+#[napi(object)]
+struct ProcStat {
+  /// Single-character process state (`R`, `S`, `D`, `Z`, `T`, ...).
+  pub state: String,
+  /// CPU time spent in user mode, in milliseconds.
+  pub utime: i64,
+  /// CPU time spent in kernel mode, in milliseconds.
+  pub stime: i64,
+  /// Resident set size, in bytes.
+  pub rss: i64,
+}
+
+/// ioctls that `Pty::ioctl` passes through: only ones that take no argument or a plain integer
+/// value, never a pointer the kernel would dereference.
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)]
+const SAFE_IOCTLS: &[u64] = &[
+  libc::TIOCSBRK as u64,
+  libc::TIOCCBRK as u64,
+  libc::TIOCEXCL as u64,
+  libc::TIOCNXCL as u64,
+  libc::TCSBRK as u64,
+  libc::TCSBRKP as u64,
+  libc::TCXONC as u64,
+  libc::TCFLSH as u64,
+  libc::TIOCSIG as u64,
+  libc::TIOCVHANGUP as u64,
+];
+
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::unnecessary_cast)]
+const SAFE_IOCTLS: &[u64] = &[
+  libc::TIOCSBRK as u64,
+  libc::TIOCCBRK as u64,
+  libc::TIOCEXCL as u64,
+  libc::TIOCNXCL as u64,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum SpawnMode {
+  Pty,
+  Pipe,
+}
+
+impl SpawnMode {
+  fn parse(mode: Option<&str>) -> Result<Self, PtyError> {
+    match mode {
+      None | Some("pty") => Ok(SpawnMode::Pty),
+      Some("pipe") => Ok(SpawnMode::Pipe),
+      Some(mode) => Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("invalid mode: {}", mode),
+      )),
     }
+  }
+}
 
-    let ts_on_exit: ThreadsafeFunction<i32, ErrorStrategy::CalleeHandled> = on_exit
-      .create_threadsafe_function(0, |ctx| ctx.env.create_int32(ctx.value).map(|v| vec![v]))?;
+/// `TERM` for pty children when neither the options nor the parent's environment set one.
+const DEFAULT_TERM: &str = "xterm-256color";
 
-    let mut child = cmd
-      .spawn()
-      .map_err(|err| NAPI_ERROR::new(GenericFailure, err))?;
+/// Line speeds accepted by `set_speed`, i.e. those with a `B*` constant (at least on Linux).
+const BAUD_RATES: &[u32] = &[
+  50, 75, 110, 134, 150, 200, 300, 600, 1200, 1800, 2400, 4800, 9600, 19200, 38400, 57600, 115200,
+  230400, 460800, 500000, 576000, 921600, 1000000, 1152000, 1500000, 2000000, 2500000, 3000000,
+  3500000, 4000000,
+];
 
-    let pid = child.id();
+/// Upper bound on `PtyOptions::prepare`, which is meant for a bit of setup, not a script.
+const MAX_PREPARE_STEPS: usize = 32;
 
-    set_nonblocking(fd_controller)?;
+/// A validated `PrepareStep`, ready to run between `fork` and `exec`.
+enum PrepareOp {
+  Mkdir(CString, libc::mode_t),
+  Touch(CString, libc::mode_t),
+  Chmod(CString, libc::mode_t),
+}
 
-    let file = File::from(pty_pair.controller);
-    let fd = file.as_raw_fd();
+impl PrepareOp {
+  fn parse(step: &PrepareStep) -> Result<Self, PtyError> {
+    let invalid = |reason: &str| {
+      PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!(
+          "invalid prepare step {:?} on {:?}: {}",
+          step.op, step.path, reason
+        ),
+      )
+    };
 
-    // We're creating a new thread for every child, this uses a bit more system resources compared
-    // to alternatives (below), trading off simplicity of implementation.
-    //
-    // The alternatives:
-    // - Mandate that every single `wait` goes through a central process-wide loop that knows
-    //   about all processes (this is what `pid1` does), but needs a bit of care and some static
-    //   analysis to ensure that every single call goes through the wrapper to avoid double `wait`'s
-    //   on a child.
-    // - Have a single thread loop where other entities can register children (by sending the pid
-    //   over a channel) and this loop can use `epoll` to listen for each child's `pidfd` for when
-    //   they are ready to be `wait`'ed. This has the inconvenience that it consumes one FD per child.
-    //
-    // For discussion check out: https://github.com/replit/ruspty/pull/1#discussion_r1463672548
-    thread::spawn(move || {
-      match child.wait() {
-        Ok(status) => {
-          if status.success() {
-            ts_on_exit.call(Ok(0), ThreadsafeFunctionCallMode::Blocking);
+    if step.path.is_empty() {
+      return Err(invalid("empty path"));
+    }
+    let path = CString::new(step.path.as_str()).map_err(|_| invalid("path contains a NUL byte"))?;
+    if step.mode.is_some_and(|mode| mode > 0o7777) {
+      return Err(invalid("mode out of range"));
+    }
+    let mode = step.mode.map(|mode| mode as libc::mode_t);
+
+    match step.op.as_str() {
+      "mkdir" => Ok(PrepareOp::Mkdir(path, mode.unwrap_or(0o755))),
+      "touch" => Ok(PrepareOp::Touch(path, mode.unwrap_or(0o644))),
+      "chmod" => Ok(PrepareOp::Chmod(
+        path,
+        mode.ok_or_else(|| invalid("mode is required"))?,
+      )),
+      _ => Err(invalid("unknown op")),
+    }
+  }
+
+  /// Runs in the forked child, so it sticks to async-signal-safe calls.
+  fn run(&self) -> Result<(), Error> {
+    let res = unsafe {
+      match self {
+        PrepareOp::Mkdir(path, mode) => {
+          let res = libc::mkdir(path.as_ptr(), *mode);
+          if res == -1 && Error::last_os_error().kind() == ErrorKind::AlreadyExists {
+            0
           } else {
-            ts_on_exit.call(
-              Ok(status.code().unwrap_or(-1)),
-              ThreadsafeFunctionCallMode::Blocking,
-            );
+            res
           }
         }
-        Err(err) => {
-          ts_on_exit.call(
-            Err(NAPI_ERROR::new(
-              GenericFailure,
-              format!(
-                "OS error when waiting for child process to exit: {}",
-                err.raw_os_error().unwrap_or(-1)
-              ),
-            )),
-            ThreadsafeFunctionCallMode::Blocking,
+        PrepareOp::Touch(path, mode) => {
+          let fd = libc::open(
+            path.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_NOCTTY | libc::O_CLOEXEC,
+            *mode as libc::c_uint,
           );
+          if fd == -1 {
+            -1
+          } else {
+            libc::close(fd)
+          }
         }
+        PrepareOp::Chmod(path, mode) => libc::chmod(path.as_ptr(), *mode),
       }
+    };
 
-      // Close the fd once we return from `child.wait()`.
-      unsafe {
-        rustix::io::close(fd);
-      }
-    });
+    if res == -1 {
+      return Err(Error::last_os_error());
+    }
 
-    Ok(Pty { file, fd, pid })
+    Ok(())
   }
+}
 
-  #[napi]
-  #[allow(dead_code)]
-  pub fn resize(&mut self, size: Size) -> Result<(), NAPI_ERROR> {
-    let window_size = Winsize {
-      ws_col: size.cols,
-      ws_row: size.rows,
-      ws_xpixel: 0,
-      ws_ypixel: 0,
-    };
+/// Where one of the child's stdio streams goes, see `PtyOptions::stdin`.
+#[derive(Clone, Copy, PartialEq)]
+enum StdioMode {
+  Pty,
+  Pipe,
+  Null,
+  Inherit,
+}
 
-    let res = unsafe { libc::ioctl(self.fd, libc::TIOCSWINSZ, &window_size as *const _) };
+impl StdioMode {
+  /// Parses the option for the stream `name`, unset if it should go with the `SpawnMode`'s default.
+  fn parse(name: &str, value: Option<&str>) -> Result<Option<Self>, PtyError> {
+    match value {
+      None => Ok(None),
+      Some("pty") => Ok(Some(StdioMode::Pty)),
+      Some("pipe") => Ok(Some(StdioMode::Pipe)),
+      Some("null") => Ok(Some(StdioMode::Null)),
+      Some("inherit") => Ok(Some(StdioMode::Inherit)),
+      Some(value) => Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("invalid {}: {}", name, value),
+      )),
+    }
+  }
 
-    if res != 0 {
-      return Err(NAPI_ERROR::new(
-        napi::Status::GenericFailure,
-        format!("ioctl TIOCSWINSZ failed: {}", Error::last_os_error()),
-      ));
+  /// Parses `stderr`, which `split_stderr` is a shorthand for.
+  fn parse_stderr(opts: &PtyOptions) -> Result<Option<Self>, PtyError> {
+    let stderr = StdioMode::parse("stderr", opts.stderr.as_deref())?;
+    if !opts.split_stderr.unwrap_or(false) {
+      return Ok(stderr);
     }
 
-    Ok(())
+    match stderr {
+      None | Some(StdioMode::Pipe) => Ok(Some(StdioMode::Pipe)),
+      Some(_) => Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!(
+          "split_stderr conflicts with stderr \"{}\"",
+          opts.stderr.as_deref().unwrap_or_default()
+        ),
+      )),
+    }
+  }
+
+  /// The `Stdio` for the modes that don't depend on how the rest of the child's io is set up.
+  /// Callers handle pipes and the modes their `SpawnMode` supports first, so the others end up
+  /// here.
+  fn standalone(self, name: &str) -> Result<Stdio, PtyError> {
+    match self {
+      StdioMode::Null => Ok(Stdio::null()),
+      StdioMode::Inherit => Ok(Stdio::inherit()),
+      StdioMode::Pty => Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("{} \"pty\" requires mode \"pty\"", name),
+      )),
+      StdioMode::Pipe => unreachable!("every SpawnMode sets up its own pipes"),
+    }
+  }
+}
+
+/// The parent's side of the child's stdio.
+struct ChildIo {
+  /// Where the child's output is read from.
+  controller: OwnedFd,
+  /// Where the child's input is written to, when it isn't `controller`.
+  input: Option<OwnedFd>,
+  /// The pty's user side, which has to stay open until the child is spawned.
+  user: Option<OwnedFd>,
+  /// Where the child's stdout is read from, when it isn't `controller`.
+  stdout: Option<OwnedFd>,
+  /// Where the child's stderr is read from, when it isn't `controller`.
+  stderr: Option<OwnedFd>,
+  tty_dev: Option<libc::dev_t>,
+}
+
+fn setup_pty_io(cmd: &mut Command, opts: &PtyOptions) -> Result<ChildIo, PtyError> {
+  let window_size = Winsize {
+    ws_col: opts.size.cols,
+    ws_row: opts.size.rows,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+  };
+
+  let pty_pair =
+    openpty(None, Some(&window_size)).map_err(|err| PtyError::new(ErrorCode::SpawnFailed, err))?;
+
+  let fd_controller = pty_pair.controller.as_raw_fd();
+  let fd_user = pty_pair.user.as_raw_fd();
+  // Every controller is a clone of `/dev/ptmx`, so the user side is the one with a distinct
+  // device number. The parent closes it after spawning, so grab it now.
+  let tty_dev = get_tty_dev(fd_user)?;
+
+  if let Ok(mut termios) = termios::tcgetattr(&pty_pair.controller) {
+    termios.input_modes.set(InputModes::IUTF8, true);
+    if opts.raw_output.unwrap_or(false) {
+      termios.output_modes.set(OutputModes::OPOST, false);
+    }
+    termios::tcsetattr(&pty_pair.controller, OptionalActions::Now, &termios)
+      .map_err(|err| PtyError::new(ErrorCode::SpawnFailed, err))?;
+  }
+
+  if opts.packet_mode.unwrap_or(false) {
+    set_packet_mode(fd_controller)?;
+  }
+
+  // Every stdio stream gets its own duplicate, as `cmd` closes them when it is dropped. The
+  // duplicates are `O_CLOEXEC`, only their copies on stdio survive `exec`.
+  let user = || {
+    pty_pair
+      .user
+      .try_clone()
+      .map_err(|err| PtyError::new(ErrorCode::SpawnFailed, format!("failed to dup fd: {}", err)))
+  };
+
+  let stdin = StdioMode::parse("stdin", opts.stdin.as_deref())?.unwrap_or(StdioMode::Pty);
+  let stdout = StdioMode::parse("stdout", opts.stdout.as_deref())?.unwrap_or(StdioMode::Pty);
+  let stderr = StdioMode::parse_stderr(opts)?.unwrap_or(StdioMode::Pty);
+
+  // With `stdin_data` the caller sets up stdin.
+  let input = match stdin {
+    _ if opts.stdin_data.is_some() => None,
+    StdioMode::Pty => {
+      cmd.stdin(user()?);
+      None
+    }
+    StdioMode::Pipe => {
+      let (stdin_reader, stdin_writer) = std::io::pipe().map_err(pipe_err)?;
+      cmd.stdin(stdin_reader);
+      Some(OwnedFd::from(stdin_writer))
+    }
+    other => {
+      cmd.stdin(other.standalone("stdin")?);
+      None
+    }
+  };
+
+  let stdout = match stdout {
+    StdioMode::Pty => {
+      cmd.stdout(user()?);
+      None
+    }
+    StdioMode::Pipe => {
+      let (stdout_reader, stdout_writer) = std::io::pipe().map_err(pipe_err)?;
+      cmd.stdout(stdout_writer);
+      Some(OwnedFd::from(stdout_reader))
+    }
+    other => {
+      cmd.stdout(other.standalone("stdout")?);
+      None
+    }
+  };
+
+  let stderr = match stderr {
+    StdioMode::Pty => {
+      cmd.stderr(user()?);
+      None
+    }
+    StdioMode::Pipe => Some(setup_stderr_pipe(cmd)?),
+    other => {
+      cmd.stderr(other.standalone("stderr")?);
+      None
+    }
+  };
+
+  Ok(ChildIo {
+    controller: pty_pair.controller,
+    input,
+    user: Some(pty_pair.user),
+    stdout,
+    stderr,
+    tty_dev: Some(tty_dev),
+  })
+}
+
+/// Whether `cmd`'s program can be found the way `exec` looks for it: relative to the child's
+/// working directory if it contains a `/`, on the child's `PATH` otherwise. Used to tell a missing
+/// command apart from other `ENOENT`s during spawn, e.g. from `prepare` steps or a missing `dir`.
+fn command_exists(cmd: &Command) -> bool {
+  let dir = cmd.get_current_dir().unwrap_or(std::path::Path::new("."));
+  let program = std::path::Path::new(cmd.get_program());
+
+  if program.as_os_str().as_bytes().contains(&b'/') {
+    return dir.join(program).exists();
+  }
+
+  let path = match cmd.get_envs().find(|(key, _)| *key == "PATH") {
+    Some((_, path)) => path.map(ToOwned::to_owned),
+    None => std::env::var_os("PATH"),
+  };
+
+  path.as_deref().is_some_and(|path| {
+    std::env::split_paths(path).any(|entry| dir.join(entry).join(program).exists())
+  })
+}
+
+fn pipe_err(err: Error) -> PtyError {
+  PtyError::new(ErrorCode::SpawnFailed, format!("pipe failed: {}", err))
+}
+
+fn setup_pipe_io(cmd: &mut Command, opts: &PtyOptions) -> Result<ChildIo, PtyError> {
+  let stdin = StdioMode::parse("stdin", opts.stdin.as_deref())?.unwrap_or(StdioMode::Pipe);
+  let stdout = StdioMode::parse("stdout", opts.stdout.as_deref())?.unwrap_or(StdioMode::Pipe);
+  let stderr = StdioMode::parse_stderr(opts)?;
+
+  // Both pipes are created with `O_CLOEXEC`, only the ends `dup2`'d onto stdio survive `exec`.
+  // With `stdin_data` the caller sets up stdin instead.
+  let stdin_writer = match stdin {
+    _ if opts.stdin_data.is_some() => None,
+    StdioMode::Pipe => {
+      let (stdin_reader, stdin_writer) = std::io::pipe().map_err(pipe_err)?;
+      cmd.stdin(stdin_reader);
+      Some(OwnedFd::from(stdin_writer))
+    }
+    other => {
+      cmd.stdin(other.standalone("stdin")?);
+      None
+    }
+  };
+  let (output_reader, output_writer) = std::io::pipe().map_err(pipe_err)?;
+
+  if let Some(size) = opts.pipe_size {
+    set_pipe_size(output_reader.as_raw_fd(), size)?;
+  }
+
+  // Unless asked for a pipe of its own, stderr shares the output pipe with stdout.
+  let stderr = match stderr {
+    None => {
+      cmd.stderr(output_writer.try_clone().map_err(pipe_err)?);
+      None
+    }
+    Some(StdioMode::Pipe) => Some(setup_stderr_pipe(cmd)?),
+    Some(other) => {
+      cmd.stderr(other.standalone("stderr")?);
+      None
+    }
+  };
+
+  // If neither stdout nor stderr go to the output pipe, reading `fd` sees EOF right away.
+  match stdout {
+    StdioMode::Pipe => cmd.stdout(output_writer),
+    other => cmd.stdout(other.standalone("stdout")?),
+  };
+
+  Ok(ChildIo {
+    controller: OwnedFd::from(output_reader),
+    input: stdin_writer,
+    user: None,
+    stdout: None,
+    stderr,
+    tty_dev: None,
+  })
+}
+
+#[cfg(target_os = "linux")]
+fn set_pipe_size(fd: c_int, size: u32) -> Result<(), PtyError> {
+  let size = c_int::try_from(size).map_err(|_| {
+    PtyError::new(
+      ErrorCode::InvalidArgument,
+      format!("invalid pipe_size: {}", size),
+    )
+  })?;
+
+  if unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, size) } == -1 {
+    return Err(PtyError::new(
+      ErrorCode::SpawnFailed,
+      format!("fcntl F_SETPIPE_SZ failed: {}", Error::last_os_error()),
+    ));
+  }
+
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_pipe_size(_fd: c_int, _size: u32) -> Result<(), PtyError> {
+  Err(PtyError::new(
+    ErrorCode::Unsupported,
+    "pipe_size is only supported on Linux".to_string(),
+  ))
+}
+
+/// Points the child's stderr at a pipe of its own, returning the read end.
+fn setup_stderr_pipe(cmd: &mut Command) -> Result<OwnedFd, PtyError> {
+  let (reader, writer) = std::io::pipe().map_err(pipe_err)?;
+  cmd.stderr(writer);
+
+  Ok(OwnedFd::from(reader))
+}
+
+#[allow(dead_code)]
+fn set_controlling_terminal(fd: c_int) -> Result<(), Error> {
+  let res = unsafe {
+    #[allow(clippy::cast_lossless)]
+    libc::ioctl(fd, TIOCSCTTY as _, 0)
+  };
+
+  if res != 0 {
+    return Err(Error::last_os_error());
   }
+
+  Ok(())
+}
+
+#[allow(dead_code)]
+fn get_tty_dev(fd: c_int) -> Result<libc::dev_t, PtyError> {
+  let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+  let res = unsafe { libc::fstat(fd, stat.as_mut_ptr()) };
+
+  if res != 0 {
+    return Err(PtyError::new(
+      ErrorCode::SpawnFailed,
+      format!("fstat failed: {}", Error::last_os_error()),
+    ));
+  }
+
+  Ok(unsafe { stat.assume_init() }.st_rdev)
+}
+
+/// Opens a fresh handle to the pty's user side. The parent closes its original copy right after
+/// spawning, so that the controller sees the hangup once the child exits.
+fn open_user_side(controller: BorrowedFd) -> Result<File, PtyError> {
+  let path = ptsname(controller, Vec::new())
+    .map_err(|err| PtyError::new(ErrorCode::Io, format!("ptsname failed: {}", err)))?;
+
+  std::fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .custom_flags(libc::O_NOCTTY)
+    .open(std::ffi::OsStr::from_bytes(path.as_bytes()))
+    .map_err(|err| PtyError::new(ErrorCode::Io, format!("failed to open {:?}: {}", path, err)))
+}
+
+#[allow(dead_code)]
+fn set_packet_mode(fd: c_int) -> Result<(), PtyError> {
+  let enable: c_int = 1;
+  let res = unsafe { libc::ioctl(fd, libc::TIOCPKT, &enable as *const _) };
+
+  if res != 0 {
+    return Err(PtyError::new(
+      ErrorCode::SpawnFailed,
+      format!("ioctl TIOCPKT failed: {}", Error::last_os_error()),
+    ));
+  }
+
+  Ok(())
+}
+
+#[allow(dead_code)]
+fn set_nonblocking(fd: c_int) -> Result<(), PtyError> {
+  use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
+
+  let status_flags = unsafe { fcntl(fd, F_GETFL, 0) };
+
+  if status_flags < 0 {
+    return Err(PtyError::new(
+      ErrorCode::SpawnFailed,
+      format!("fcntl F_GETFL failed: {}", Error::last_os_error()),
+    ));
+  }
+
+  let res = unsafe { fcntl(fd, F_SETFL, status_flags | O_NONBLOCK) };
+
+  if res != 0 {
+    return Err(PtyError::new(
+      ErrorCode::SpawnFailed,
+      format!("fcntl F_SETFL failed: {}", Error::last_os_error()),
+    ));
+  }
+
+  Ok(())
+}
+
+/// Checks that `fd` can be handed to the child as `cgroup_procs_fd`.
+fn check_cgroup_procs_fd(fd: c_int) -> Result<(), PtyError> {
+  if cfg!(not(target_os = "linux")) {
+    return Err(PtyError::new(
+      ErrorCode::Unsupported,
+      "cgroup_procs_fd is only supported on Linux".to_string(),
+    ));
+  }
+
+  check_writable_fd("cgroup_procs_fd", fd)
+}
+
+/// Checks that `fd` can be handed to the child as `log_fd`, and returns the fd number it gets
+/// there.
+fn check_log_fd(fd: c_int, target: Option<c_int>) -> Result<c_int, PtyError> {
+  check_writable_fd("log_fd", fd)?;
+
+  let target = target.unwrap_or(3);
+  let max_fds = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+  if target <= libc::STDERR_FILENO || (max_fds > 0 && libc::c_long::from(target) >= max_fds) {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      format!("invalid log_fd_target: {}", target),
+    ));
+  }
+
+  Ok(target)
+}
+
+fn check_writable_fd(name: &str, fd: c_int) -> Result<(), PtyError> {
+  // The child's stdio is already in place once these are used, so 0-2 would be the child's own.
+  if fd <= libc::STDERR_FILENO {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      format!("{} {} is a stdio fd", name, fd),
+    ));
+  }
+
+  let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+  if flags == -1 {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      format!("invalid {} {}: {}", name, fd, Error::last_os_error()),
+    ));
+  }
+
+  if flags & libc::O_ACCMODE == libc::O_RDONLY {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      format!("{} {} is not open for writing", name, fd),
+    ));
+  }
+
+  Ok(())
+}
+
+/// Makes `fd` available to the command as `target`, surviving `exec`.
+fn inherit_fd_as(fd: c_int, target: c_int) -> Result<(), Error> {
+  // `dup2` is a no-op when both are the same, so the flag has to be cleared by hand then.
+  let res = if fd == target {
+    unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }
+  } else {
+    unsafe { libc::dup2(fd, target) }
+  };
+
+  if res == -1 {
+    return Err(Error::last_os_error());
+  }
+
+  Ok(())
+}
+
+/// Writes the calling process's pid to `fd` in decimal. This runs between `fork` and `exec`, so
+/// it formats the pid on the stack instead of allocating.
+fn write_own_pid(fd: c_int) -> Result<(), Error> {
+  let mut buf = [0u8; 10];
+  let mut pid = unsafe { libc::getpid() } as u32;
+  let mut start = buf.len();
+
+  loop {
+    start -= 1;
+    buf[start] = b'0' + (pid % 10) as u8;
+    pid /= 10;
+    if pid == 0 {
+      break;
+    }
+  }
+
+  let digits = &buf[start..];
+  let res = unsafe { libc::write(fd, digits.as_ptr() as *const _, digits.len()) };
+  if res == -1 {
+    return Err(Error::last_os_error());
+  }
+
+  Ok(())
+}
+
+/// Wraps `on_exit` to be called from the thread that reaps the child. Only `ExitState` holds on to
+/// it, and only until it fired.
+fn exit_callback(on_exit: &JsFunction) -> Result<ExitCallback, NAPI_ERROR> {
+  on_exit.create_threadsafe_function(0, |ctx| ctx.env.create_int32(ctx.value).map(|v| vec![v]))
+}
+
+/// Records the result of waiting for the child (along with the stats of `cgroup_path`, if any)
+/// and fires `on_exit` (if any) with the exit code, which is `-1` when the child was killed by a
+/// signal.
+fn notify_exit(
+  exit_state: &ExitState,
+  cgroup_path: Option<&str>,
+  status: std::io::Result<ExitStatus>,
+) -> Result<ExitInfo, NAPI_ERROR> {
+  let exit_info = status
+    .map(|status| {
+      let mut info = ExitInfo::from(status);
+      if let Some(cgroup_path) = cgroup_path {
+        info.read_cgroup_stats(cgroup_path);
+      }
+      info
+    })
+    .map_err(|err| {
+      NAPI_ERROR::new(
+        GenericFailure,
+        format!(
+          "OS error when waiting for child process to exit: {}",
+          err.raw_os_error().unwrap_or(-1)
+        ),
+      )
+    });
+
+  exit_state.set(exit_info.clone());
+  exit_state.fire_on_exit(&exit_info);
+
+  exit_info
+}
+
+/// Blocks until `fd` is ready for the given poll `events`, retrying on `EINTR`.
+fn poll_fd(fd: c_int, events: libc::c_short) -> Result<libc::c_short, Error> {
+  let mut poll_fd = libc::pollfd {
+    fd,
+    events,
+    revents: 0,
+  };
+
+  loop {
+    let res = unsafe { libc::poll(&mut poll_fd, 1, -1) };
+
+    if res >= 0 {
+      return Ok(poll_fd.revents);
+    }
+
+    let err = Error::last_os_error();
+    if err.kind() != ErrorKind::Interrupted {
+      return Err(err);
+    }
+  }
+}
+
+fn open_output_log(path: &str) -> Result<File, PtyError> {
+  File::options()
+    .create(true)
+    .append(true)
+    .open(path)
+    .map_err(|err| {
+      PtyError::new(
+        ErrorCode::Io,
+        format!("failed to open output log {}: {}", path, err),
+      )
+    })
+}
+
+/// Appends `data` to the output log, if any. The log is dropped after the first failed write, so
+/// that a full disk doesn't get in the way of the output itself.
+fn tee_output(output_log: &mut Option<File>, data: &[u8]) {
+  if let Some(file) = output_log {
+    if file.write_all(data).is_err() {
+      *output_log = None;
+    }
+  }
+}
+
+/// `max_output_bytes` as a length, unlimited when unset.
+fn max_output_bytes(opts: &PtyOptions) -> Result<usize, PtyError> {
+  match opts.max_output_bytes {
+    Some(max) => usize::try_from(max).map_err(|_| {
+      PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("invalid max_output_bytes: {}", max),
+      )
+    }),
+    None => Ok(usize::MAX),
+  }
+}
+
+/// How much output `forward_output` passes on, see `max_output_bytes`.
+struct OutputLimit {
+  max_bytes: usize,
+  /// With `kill_on_output_limit`, the child's process group and its exit state, so that a group
+  /// whose leader was already reaped isn't signalled.
+  kill: Option<(i32, Arc<ExitState>)>,
+}
+
+/// Feeds everything read from `file`, a duplicate of the controller fd, to `on_data` (and to the
+/// output log, if any) until `limit` is reached, and discards the rest. Runs until EOF, or until
+/// the controller was closed and nothing is left to read.
+fn forward_output(
+  controller: Arc<Controller>,
+  file: File,
+  on_data: DataCallback,
+  mut output_log: Option<File>,
+  mut limit: OutputLimit,
+) {
+  let fd = file.as_raw_fd();
+  let mut buf = [0u8; 16 * 1024];
+  let mut forwarded = 0;
+
+  loop {
+    let res = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+
+    if res > 0 {
+      let data = &buf[..res as usize];
+      tee_output(&mut output_log, data);
+      let room = limit.max_bytes - forwarded;
+      if room > 0 {
+        let chunk = &data[..data.len().min(room)];
+        forwarded += chunk.len();
+        // Blocks while the queue is full, which is what pushes back on the child.
+        on_data.call(chunk.to_vec(), ThreadsafeFunctionCallMode::Blocking);
+      }
+
+      if data.len() > room {
+        if let Some((pgid, exit_state)) = limit.kill.take() {
+          // Keep reading until EOF, so the child doesn't block on a full buffer before it dies.
+          if exit_state.get().is_none() {
+            unsafe { libc::killpg(pgid, libc::SIGKILL) };
+          }
+        }
+      }
+      continue;
+    }
+    if res == 0 {
+      return;
+    }
+
+    // Reading the controller fails with `EIO` once the child's side was closed.
+    let err = Error::last_os_error();
+    match err.kind() {
+      ErrorKind::Interrupted => {}
+      ErrorKind::WouldBlock => {
+        let mut poll_fd = libc::pollfd {
+          fd,
+          events: libc::POLLIN,
+          revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut poll_fd, 1, READER_POLL_TIMEOUT_MS) };
+
+        if res == 0 && controller.is_released() {
+          return;
+        }
+      }
+      _ => return,
+    }
+  }
+}
+
+struct WriteAll {
+  // A duplicate, so that the fd can't be closed (and its number reused) under a pending write.
+  file: File,
+  data: Buffer,
+}
+
+impl Task for WriteAll {
+  type Output = ();
+  type JsValue = ();
+
+  fn compute(&mut self) -> Result<(), NAPI_ERROR> {
+    let mut written = 0;
+
+    while written < self.data.len() {
+      let remaining = &self.data[written..];
+      let res = unsafe {
+        libc::write(
+          self.file.as_raw_fd(),
+          remaining.as_ptr() as *const _,
+          remaining.len(),
+        )
+      };
+
+      if res >= 0 {
+        written += res as usize;
+        continue;
+      }
+
+      let err = Error::last_os_error();
+      match err.kind() {
+        ErrorKind::Interrupted => {}
+        // The controller fd is nonblocking, so wait for the child to drain its input queue.
+        ErrorKind::WouldBlock => {
+          poll_fd(self.file.as_raw_fd(), libc::POLLOUT)
+            .map_err(|err| NAPI_ERROR::new(GenericFailure, format!("poll failed: {}", err)))?;
+        }
+        _ => {
+          return Err(NAPI_ERROR::new(
+            GenericFailure,
+            format!("write failed: {}", err),
+          ))
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  fn resolve(&mut self, _env: Env, _output: ()) -> Result<(), NAPI_ERROR> {
+    Ok(())
+  }
+}
+
+struct Terminate {
+  pid: u32,
+  grace: Duration,
+  exit_state: Arc<ExitState>,
+}
+
+impl Task for Terminate {
+  type Output = i32;
+  type JsValue = i32;
+
+  fn compute(&mut self) -> Result<i32, NAPI_ERROR> {
+    // Never signal a pid that was already reaped, it may have been reused by now.
+    if let Some(status) = self.exit_state.get() {
+      return status.map(|info| info.exit_code.unwrap_or(-1));
+    }
+
+    unsafe {
+      libc::kill(self.pid as i32, libc::SIGTERM);
+    }
+
+    let status = match self.exit_state.wait_timeout(self.grace) {
+      Some(status) => status,
+      None => {
+        unsafe {
+          libc::kill(self.pid as i32, libc::SIGKILL);
+        }
+
+        self.exit_state.wait()
+      }
+    };
+
+    status.map(|info| info.exit_code.unwrap_or(-1))
+  }
+
+  fn resolve(&mut self, _env: Env, exit_code: i32) -> Result<i32, NAPI_ERROR> {
+    Ok(exit_code)
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc_stat(pid: u32) -> Result<ProcStat, PtyError> {
+  let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).map_err(|err| {
+    if err.kind() == ErrorKind::NotFound {
+      PtyError::new(
+        ErrorCode::ChildExited,
+        format!("process {} has exited", pid),
+      )
+    } else {
+      PtyError::new(
+        ErrorCode::Io,
+        format!("failed to read /proc/{}/stat: {}", pid, err),
+      )
+    }
+  })?;
+
+  // The command name is wrapped in parens and may itself contain spaces or parens, so the
+  // remaining fields start after the *last* closing paren.
+  let fields: Vec<&str> = stat
+    .rsplit_once(')')
+    .map(|(_, rest)| rest.split_whitespace().collect())
+    .unwrap_or_default();
+
+  let parse_field = |idx: usize| -> Result<i64, PtyError> {
+    fields
+      .get(idx)
+      .and_then(|field| field.parse().ok())
+      .ok_or_else(|| PtyError::new(ErrorCode::Io, format!("malformed /proc/{}/stat", pid)))
+  };
+
+  // Indices are relative to the state field, which is field 3 in proc(5).
+  let state = fields
+    .first()
+    .map(|state| state.to_string())
+    .ok_or_else(|| PtyError::new(ErrorCode::Io, format!("malformed /proc/{}/stat", pid)))?;
+  let utime = parse_field(11)?;
+  let stime = parse_field(12)?;
+  let rss = parse_field(21)?;
+
+  let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1);
+  let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1);
+
+  Ok(ProcStat {
+    state,
+    utime: utime * 1000 / ticks_per_sec,
+    stime: stime * 1000 / ticks_per_sec,
+    rss: rss * page_size,
+  })
+}
+
+impl Pty {
+  /// Fails once ownership of the controller fd was handed over through `take_fd`.
+  fn ensure_fd_owned(&self) -> Result<(), PtyError> {
+    if self.fd_taken {
+      return Err(PtyError::new(
+        ErrorCode::FdTaken,
+        "controller fd was taken".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Runs `f` on the controller fd, which stays open until it returns. Fails once ownership was
+  /// handed over through `take_fd`, or once the fd was closed after the child exited, as its
+  /// number may belong to an unrelated file by then.
+  fn with_controller<T>(
+    &self,
+    f: impl FnOnce(BorrowedFd) -> Result<T, PtyError>,
+  ) -> Result<T, PtyError> {
+    self.ensure_fd_owned()?;
+
+    self.controller.with_fd(f).unwrap_or_else(|| {
+      Err(PtyError::new(
+        ErrorCode::ChildExited,
+        "controller fd was closed after the child exited".to_string(),
+      ))
+    })
+  }
+
+  /// A duplicate of the fd the child's input is written to (the controller fd in pty mode), for
+  /// work that outlives the call.
+  fn input_file(&self) -> Result<File, PtyError> {
+    let dup_err = |err: Error| PtyError::new(ErrorCode::Io, format!("failed to dup fd: {}", err));
+
+    match &self.input {
+      Some(input) => input.try_clone().map_err(dup_err),
+      None => self.with_controller(|fd| fd.try_clone_to_owned().map(File::from).map_err(dup_err)),
+    }
+  }
+
+  /// Fails once the child was reaped: its pid (and so the group id) may have been reused by an
+  /// unrelated process since, which must never be signalled, inspected or moved.
+  fn ensure_running(&self) -> Result<(), PtyError> {
+    if self.exit_state.get().is_some() {
+      return Err(PtyError::new(
+        ErrorCode::ChildExited,
+        format!("process {} has exited", self.pid),
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Fails in pipe mode, where there is no tty to operate on.
+  fn require_tty(&self) -> Result<(), PtyError> {
+    if self.tty_dev.is_none() {
+      return Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "there is no tty in pipe mode".to_string(),
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// The terminal's current window size, which only exists in pty mode.
+  fn window_size(&self) -> Result<Size, PtyError> {
+    self.require_tty()?;
+
+    let window_size = self.with_controller(|fd| {
+      termios::tcgetwinsize(fd)
+        .map_err(|err| PtyError::new(ErrorCode::Io, format!("ioctl TIOCGWINSZ failed: {}", err)))
+    })?;
+
+    Ok(Size {
+      cols: window_size.ws_col,
+      rows: window_size.ws_row,
+    })
+  }
+
+  /// Sets the terminal's window size, which only exists in pty mode.
+  fn set_window_size(&self, size: &Size) -> Result<(), PtyError> {
+    self.require_tty()?;
+
+    let window_size = Winsize {
+      ws_col: size.cols,
+      ws_row: size.rows,
+      ws_xpixel: 0,
+      ws_ypixel: 0,
+    };
+
+    self.with_controller(|fd| {
+      let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &window_size as *const _) };
+
+      if res != 0 {
+        return Err(PtyError::new(
+          ErrorCode::Io,
+          format!("ioctl TIOCSWINSZ failed: {}", Error::last_os_error()),
+        ));
+      }
+
+      Ok(())
+    })
+  }
+
+  /// Sends `signal` to the child's process group, unless the child was already reaped.
+  fn signal_group(&self, signal: c_int) -> Result<(), PtyError> {
+    self.ensure_running()?;
+
+    if unsafe { libc::killpg(self.pgid, signal) } == -1 {
+      return Err(PtyError::new(
+        ErrorCode::Io,
+        format!(
+          "failed to signal process group {}: {}",
+          self.pgid,
+          Error::last_os_error()
+        ),
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// The terminal's current attributes, which only exist in pty mode.
+  fn termios(&self) -> Result<termios::Termios, PtyError> {
+    self.require_tty()?;
+
+    self.with_controller(|fd| {
+      termios::tcgetattr(fd)
+        .map_err(|err| PtyError::new(ErrorCode::Io, format!("tcgetattr failed: {}", err)))
+    })
+  }
+
+  fn spawn(
+    opts: PtyOptions,
+    on_exit: Option<JsFunction>,
+    on_data: Option<JsFunction>,
+  ) -> Result<Pty, PtyError> {
+    if let Some(pgid) = opts.pgid {
+      if pgid < 0 {
+        return Err(PtyError::new(
+          ErrorCode::InvalidArgument,
+          format!("invalid pgid: {}", pgid),
+        ));
+      }
+    }
+
+    let mode = SpawnMode::parse(opts.mode.as_deref())?;
+    if mode == SpawnMode::Pipe
+      && (opts.packet_mode.unwrap_or(false) || opts.raw_output.unwrap_or(false))
+    {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "packet_mode and raw_output require mode \"pty\"".to_string(),
+      ));
+    }
+    if mode == SpawnMode::Pty && opts.pipe_size.is_some() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "pipe_size requires mode \"pipe\"".to_string(),
+      ));
+    }
+    if opts.stdin_data.is_some() && opts.stdin.is_some() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "stdin_data and stdin are mutually exclusive".to_string(),
+      ));
+    }
+
+    let mut cmd = Command::new(&opts.command);
+    cmd.args(&opts.args);
+    if let Some(argv0) = &opts.argv0 {
+      cmd.arg0(argv0);
+    }
+
+    let io = match mode {
+      SpawnMode::Pty => setup_pty_io(&mut cmd, &opts)?,
+      SpawnMode::Pipe => setup_pipe_io(&mut cmd, &opts)?,
+    };
+
+    // Fed by a thread once the child is running.
+    let stdin_data = opts
+      .stdin_data
+      .as_ref()
+      .map(|data| {
+        let (reader, writer) = std::io::pipe().map_err(pipe_err)?;
+        cmd.stdin(reader);
+        Ok::<_, PtyError>((writer, data.to_vec()))
+      })
+      .transpose()?;
+
+    let fd_controller = io.controller.as_raw_fd();
+    let fd_user = io.user.as_ref().map(|user| user.as_raw_fd());
+
+    let term = match (&opts.term, mode) {
+      (Some(term), _) => Some(term.as_str()),
+      (None, SpawnMode::Pty) if std::env::var_os("TERM").is_none_or(|term| term.is_empty()) => {
+        Some(DEFAULT_TERM)
+      }
+      (None, _) => None,
+    };
+    if let Some(term) = term {
+      cmd.env("TERM", term);
+    }
+    if opts.size_env.unwrap_or(false) {
+      cmd.env("COLUMNS", opts.size.cols.to_string());
+      cmd.env("LINES", opts.size.rows.to_string());
+    }
+    cmd.envs(&opts.envs);
+    for pair in opts.env_pairs.iter().flatten() {
+      match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+          cmd.env(key, value);
+        }
+        _ => {
+          return Err(PtyError::new(
+            ErrorCode::InvalidArgument,
+            format!("invalid env pair {:?}, expected KEY=VALUE", pair),
+          ))
+        }
+      }
+    }
+    for key in opts.unset_envs.iter().flatten() {
+      cmd.env_remove(key);
+    }
+    cmd.current_dir(&opts.dir);
+    let dir = std::fs::canonicalize(&opts.dir)
+      .map(|dir| dir.to_string_lossy().into_owned())
+      .unwrap_or_else(|_| opts.dir.clone());
+
+    let pgid = opts.pgid;
+    let hangup_on_close = opts.hangup_on_close.unwrap_or(true);
+
+    let prepare = opts.prepare.as_deref().unwrap_or_default();
+    if prepare.len() > MAX_PREPARE_STEPS {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("too many prepare steps (max {})", MAX_PREPARE_STEPS),
+      ));
+    }
+    let prepare = prepare
+      .iter()
+      .map(PrepareOp::parse)
+      .collect::<Result<Vec<_>, _>>()?;
+
+    let cgroup_procs_fd = opts.cgroup_procs_fd;
+    if let Some(fd) = cgroup_procs_fd {
+      check_cgroup_procs_fd(fd)?;
+    }
+
+    if opts.log_fd.is_none() && opts.log_fd_target.is_some() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "log_fd_target requires log_fd".to_string(),
+      ));
+    }
+    let log_fd = opts
+      .log_fd
+      .map(|fd| check_log_fd(fd, opts.log_fd_target).map(|target| (fd, target)))
+      .transpose()?;
+
+    unsafe {
+      cmd.pre_exec(move || {
+        // Join the cgroup first, so that everything the child does is accounted to it.
+        if let Some(fd) = cgroup_procs_fd {
+          write_own_pid(fd)?;
+          libc::close(fd);
+        }
+
+        match pgid {
+          // A pty can only become the controlling terminal of a session leader, so explicit
+          // process groups live in the caller's session without one.
+          Some(pgid) => {
+            if libc::setpgid(0, pgid) == -1 {
+              return Err(Error::last_os_error());
+            }
+          }
+          None => {
+            let err = libc::setsid();
+            if err == -1 {
+              return Err(Error::other("Failed to set session id"));
+            }
+
+            if let Some(fd_user) = fd_user {
+              if hangup_on_close {
+                set_controlling_terminal(fd_user)?;
+              }
+            }
+          }
+        }
+
+        if let Some(fd_user) = fd_user {
+          libc::close(fd_user);
+          libc::close(fd_controller);
+        }
+
+        libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+        libc::signal(libc::SIGHUP, libc::SIG_DFL);
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+        libc::signal(libc::SIGALRM, libc::SIG_DFL);
+
+        for op in &prepare {
+          op.run()?;
+        }
+
+        // Last, so that nothing above closes or reuses the target fd.
+        if let Some((fd, target)) = log_fd {
+          inherit_fd_as(fd, target)?;
+        }
+
+        Ok(())
+      });
+    }
+
+    let manage_wait = opts.manage_wait.unwrap_or(true);
+
+    let exit_state = Arc::new(ExitState::default());
+    if let Some(on_exit) = &on_exit {
+      exit_state.set_on_exit(exit_callback(on_exit).map_err(napi_error)?);
+    }
+
+    if opts.output_log_path.is_some() && on_data.is_none() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "output_log_path requires on_data".to_string(),
+      ));
+    }
+    if opts.max_output_bytes.is_some() && on_data.is_none() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "max_output_bytes requires on_data".to_string(),
+      ));
+    }
+    let max_output_bytes = max_output_bytes(&opts)?;
+    let output_log = opts
+      .output_log_path
+      .as_deref()
+      .map(open_output_log)
+      .transpose()?;
+
+    // Only the reader thread holds on to `on_data`, and only until the output ends.
+    let ts_on_data: Option<DataCallback> = on_data
+      .map(|on_data| {
+        on_data.create_threadsafe_function(MAX_QUEUED_CHUNKS, |ctx| {
+          ctx
+            .env
+            .create_buffer_with_data(ctx.value)
+            .map(|buf| vec![buf.into_raw()])
+        })
+      })
+      .transpose()
+      .map_err(napi_error)?;
+    let forwards_output = ts_on_data.is_some();
+
+    let mut child = cmd.spawn().map_err(|err| {
+      if err.kind() == ErrorKind::NotFound && !command_exists(&cmd) {
+        PtyError::new(
+          ErrorCode::CommandNotFound,
+          format!("command not found: {}", opts.command),
+        )
+      } else {
+        PtyError::new(ErrorCode::SpawnFailed, err)
+      }
+    })?;
+
+    if let Some((mut writer, data)) = stdin_data {
+      // Fails with `EPIPE` if the child exits without reading everything, which is fine. Dropping
+      // the writer gives the child EOF.
+      thread::spawn(move || writer.write_all(&data));
+    }
+
+    let pid = child.id();
+    let pgid = match pgid {
+      Some(pgid) if pgid > 0 => pgid,
+      _ => pid as i32,
+    };
+
+    set_nonblocking(fd_controller)?;
+
+    let file = File::from(io.controller);
+    let fd = file.as_raw_fd();
+    let reader = ts_on_data
+      .map(|ts_on_data| {
+        file
+          .try_clone()
+          .map(|file| (file, ts_on_data))
+          .map_err(|err| PtyError::new(ErrorCode::Io, format!("failed to dup fd: {}", err)))
+      })
+      .transpose()?;
+    let controller = Arc::new(Controller::new(file));
+    if let Some((file, ts_on_data)) = reader {
+      let controller = controller.clone();
+      let limit = OutputLimit {
+        max_bytes: max_output_bytes,
+        kill: opts
+          .kill_on_output_limit
+          .unwrap_or(false)
+          .then(|| (pgid, exit_state.clone())),
+      };
+      thread::spawn(move || forward_output(controller, file, ts_on_data, output_log, limit));
+    }
+    let stdout = io.stdout.map(File::from);
+    let stdout_fd = stdout.as_ref().map(|stdout| stdout.as_raw_fd());
+    if let Some(stdout_fd) = stdout_fd {
+      set_nonblocking(stdout_fd)?;
+    }
+    let stderr = io.stderr.map(File::from);
+    let stderr_fd = stderr.as_ref().map(|stderr| stderr.as_raw_fd());
+    if let Some(stderr_fd) = stderr_fd {
+      set_nonblocking(stderr_fd)?;
+    }
+    let input = io.input.map(File::from);
+    let input_fd = input.as_ref().map_or(fd, |input| input.as_raw_fd());
+    if input_fd != fd {
+      set_nonblocking(input_fd)?;
+    }
+
+    let mut pty = Pty {
+      controller,
+      fd_taken: false,
+      fd,
+      input,
+      input_fd,
+      stdout,
+      stdout_fd,
+      stderr,
+      stderr_fd,
+      pid,
+      pgid,
+      tty_dev: io.tty_dev,
+      manage_wait,
+      child: None,
+      exit_state,
+      _live: LivePty::new(),
+      options: opts,
+      dir,
+      forwards_output,
+    };
+
+    if !manage_wait {
+      pty.child = Some(child);
+      return Ok(pty);
+    }
+
+    // We're creating a new thread for every child, this uses a bit more system resources compared
+    // to alternatives (below), trading off simplicity of implementation.
+    //
+    // The alternatives:
+    // - Mandate that every single `wait` goes through a central process-wide loop that knows
+    //   about all processes (this is what `pid1` does), but needs a bit of care and some static
+    //   analysis to ensure that every single call goes through the wrapper to avoid double `wait`'s
+    //   on a child.
+    // - Have a single thread loop where other entities can register children (by sending the pid
+    //   over a channel) and this loop can use `epoll` to listen for each child's `pidfd` for when
+    //   they are ready to be `wait`'ed. This has the inconvenience that it consumes one FD per child.
+    //
+    // For discussion check out: https://github.com/replit/ruspty/pull/1#discussion_r1463672548
+    let exit_state = pty.exit_state.clone();
+    let controller = pty.controller.clone();
+    let cgroup_path = pty.options.cgroup_path.clone();
+    thread::spawn(move || {
+      // Errors are surfaced through `on_exit` and `wait()`.
+      let _ = notify_exit(&exit_state, cgroup_path.as_deref(), child.wait());
+
+      // Close the fd once the child exited and its output was read.
+      controller.wait_drained(DRAIN_TIMEOUT);
+      drop(controller.release());
+    });
+
+    Ok(pty)
+  }
+}
+
+#[napi]
+impl Pty {
+  #[napi(constructor)]
+  #[allow(dead_code)]
+  pub fn new(mut opts: PtyOptions) -> Result<Self, PtyError> {
+    let on_exit = opts.on_exit.take();
+    let on_data = opts.on_data.take();
+
+    Pty::spawn(opts, on_exit, on_data)
+  }
+
+  /// Spawns a fresh child with the same options as this one, e.g. to restart a supervised process
+  /// after it exited. This `Pty` is left untouched. Callbacks aren't kept around for this, since
+  /// they commonly refer to the `Pty` itself and would keep it from being garbage collected, so
+  /// `on_exit` and `on_data` have to be passed again.
+  #[napi(
+    ts_args_type = "onExit?: (err: null | Error, exitCode: number) => void, onData?: (data: Buffer) => void"
+  )]
+  #[allow(dead_code)]
+  pub fn respawn(
+    &self,
+    on_exit: Option<JsFunction>,
+    on_data: Option<JsFunction>,
+  ) -> Result<Pty, PtyError> {
+    Pty::new(PtyOptions {
+      on_exit,
+      on_data,
+      ..self.options.clone()
+    })
+  }
+
+  /// Sets the callback fired once the child is reaped, e.g. after taking over a `Pty` from code
+  /// that didn't pass one. Only one is kept: this replaces the one from the options or from an
+  /// earlier call, as long as that didn't fire yet. If the child was already reaped, it fires
+  /// right away.
+  #[napi(ts_args_type = "callback: (err: null | Error, exitCode: number) => void")]
+  #[allow(dead_code)]
+  pub fn on_exit(&self, callback: JsFunction) -> Result<(), PtyError> {
+    self
+      .exit_state
+      .set_on_exit(exit_callback(&callback).map_err(napi_error)?);
+
+    Ok(())
+  }
+
+  #[napi]
+  #[allow(dead_code)]
+  pub fn resize(&mut self, size: Size) -> Result<(), PtyError> {
+    self.set_window_size(&size)
+  }
+
+  /// Re-applies the size the pty was created with and sends `SIGWINCH` to the child's process
+  /// group, e.g. when reattaching to it. The kernel only signals the child itself when the size
+  /// actually changes, so the signal is sent explicitly to make it redraw either way.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn reset_size(&self) -> Result<(), PtyError> {
+    self.set_window_size(&self.options.size)?;
+    self.signal_group(libc::SIGWINCH)
+  }
+
+  /// Resizes the pty to the `COLUMNS` and `LINES` found in `env`, e.g. a shell's environment, to
+  /// sync the pty with it when reattaching. A missing variable keeps that dimension as it is,
+  /// values beyond what a window size can hold are clamped, anything but a positive integer is
+  /// rejected.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn resize_from_env(&self, env: HashMap<String, String>) -> Result<(), PtyError> {
+    let dimension = |name: &str| -> Result<Option<u16>, PtyError> {
+      let Some(value) = env.get(name) else {
+        return Ok(None);
+      };
+
+      match value.trim().parse::<u64>() {
+        Ok(0) | Err(_) => Err(PtyError::new(
+          ErrorCode::InvalidArgument,
+          format!("invalid {}: {:?}", name, value),
+        )),
+        Ok(value) => Ok(Some(value.min(u64::from(u16::MAX)) as u16)),
+      }
+    };
+
+    let cols = dimension("COLUMNS")?;
+    let rows = dimension("LINES")?;
+    if cols.is_none() && rows.is_none() {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        "neither COLUMNS nor LINES is set".to_string(),
+      ));
+    }
+
+    let current = self.window_size()?;
+    self.set_window_size(&Size {
+      cols: cols.unwrap_or(current.cols),
+      rows: rows.unwrap_or(current.rows),
+    })
+  }
+
+  /// Returns a Promise that resolves once the controller fd is readable (or the child side has
+  /// hung up), without reading anything from it.
+  ///
+  /// This is one-shot: every call arms a single `poll` on a helper thread, so consumers should
+  /// drain the fd with their own nonblocking reads and then call `readable()` again. Since no
+  /// data is consumed here, backpressure stays entirely with the caller.
+  #[napi(ts_return_type = "Promise<void>")]
+  #[allow(dead_code)]
+  pub fn readable(&self, env: Env) -> Result<JsObject, PtyError> {
+    // Polls a duplicate, so that closing the controller meanwhile can't leave the poll watching
+    // whatever file reuses its number.
+    let file = self.with_controller(|fd| {
+      fd.try_clone_to_owned()
+        .map(File::from)
+        .map_err(|err| PtyError::new(ErrorCode::Io, format!("failed to dup fd: {}", err)))
+    })?;
+    let (deferred, promise) = env.create_deferred().map_err(napi_error)?;
+
+    thread::spawn(move || match poll_fd(file.as_raw_fd(), libc::POLLIN) {
+      Ok(_) => deferred.resolve(|_| Ok(())),
+      Err(err) => {
+        deferred.reject(NAPI_ERROR::new(
+          GenericFailure,
+          format!("poll failed: {}", err),
+        ));
+      }
+    });
+
+    Ok(promise)
+  }
+
+  /// Reads the child's current state from `/proc/<pid>/stat`. Linux only.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn proc_stat(&self) -> Result<ProcStat, PtyError> {
+    #[cfg(target_os = "linux")]
+    {
+      self.ensure_running()?;
+
+      read_proc_stat(self.pid)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+      Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "proc_stat is only supported on Linux".to_string(),
+      ))
+    }
+  }
+
+  /// Returns the child's current working directory from `/proc/<pid>/cwd`, e.g. to follow a shell
+  /// that changed directories. Linux only.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn child_cwd(&self) -> Result<String, PtyError> {
+    #[cfg(target_os = "linux")]
+    {
+      self.ensure_running()?;
+
+      std::fs::read_link(format!("/proc/{}/cwd", self.pid))
+        .map(|cwd| cwd.to_string_lossy().into_owned())
+        .map_err(|err| {
+          if err.kind() == ErrorKind::NotFound {
+            PtyError::new(
+              ErrorCode::ChildExited,
+              format!("process {} has exited", self.pid),
+            )
+          } else {
+            PtyError::new(
+              ErrorCode::Io,
+              format!("failed to read /proc/{}/cwd: {}", self.pid, err),
+            )
+          }
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+      Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "child_cwd is only supported on Linux".to_string(),
+      ))
+    }
+  }
+
+  /// Writes the whole buffer to the controller fd on a worker thread, waiting for the child to
+  /// drain its input whenever the fd would block. Resolves once every byte has been written and
+  /// rejects with the underlying error otherwise.
+  #[napi(ts_return_type = "Promise<void>")]
+  #[allow(dead_code)]
+  pub fn write_all(&self, data: Buffer) -> Result<AsyncTask<WriteAll>, PtyError> {
+    Ok(AsyncTask::new(WriteAll {
+      file: self.input_file()?,
+      data,
+    }))
+  }
+
+  /// Returns the controller fd without transferring ownership. The fd is only valid while this
+  /// `Pty` is alive and until `wait_closed()` resolves (it is closed once the child is reaped and
+  /// its output was drained, after which this fails), and it must not be closed by the caller.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn borrow_fd(&self) -> Result<c_int, PtyError> {
+    self.with_controller(|fd| Ok(fd.as_raw_fd()))
+  }
+
+  /// Moves the child into another cgroup (v2) by writing its pid into
+  /// `<cgroup_path>/cgroup.procs`. Linux only.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn move_to_cgroup(&self, cgroup_path: String) -> Result<(), PtyError> {
+    #[cfg(target_os = "linux")]
+    {
+      self.ensure_running()?;
+
+      let procs_path = std::path::Path::new(&cgroup_path).join("cgroup.procs");
+
+      std::fs::write(&procs_path, self.pid.to_string()).map_err(|err| {
+        let reason = if err.kind() == ErrorKind::PermissionDenied {
+          "permission denied (needs write access to both cgroups)".to_string()
+        } else {
+          err.to_string()
+        };
+
+        PtyError::new(
+          ErrorCode::Io,
+          format!(
+            "failed to move pid {} to {}: {}",
+            self.pid,
+            procs_path.display(),
+            reason
+          ),
+        )
+      })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+      let _ = cgroup_path;
+      Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "move_to_cgroup is only supported on Linux".to_string(),
+      ))
+    }
+  }
+
+  /// Returns the device number (`st_rdev`) of the pty's user side, e.g. to correlate it with
+  /// `/dev/pts/N` or the `tty_nr` field of `/proc/<pid>/stat`.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn tty_dev(&self) -> Result<u32, PtyError> {
+    let tty_dev = self.tty_dev.ok_or_else(|| {
+      PtyError::new(
+        ErrorCode::Unsupported,
+        "there is no tty in pipe mode".to_string(),
+      )
+    })?;
+
+    u32::try_from(tty_dev).map_err(|_| {
+      PtyError::new(
+        ErrorCode::Unsupported,
+        format!("tty device number {} does not fit in 32 bits", tty_dev),
+      )
+    })
+  }
+
+  /// Checks whether the child has exited without blocking (`WNOHANG`), for use with
+  /// `manage_wait: false`. Once the child is reaped this fires `on_exit` and, like the wait
+  /// thread would, closes the controller fd after its remaining output was read (or at most a
+  /// second later). Returns the exit code, or `null` while the child is still running.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn try_reap(&mut self) -> Result<Option<i32>, PtyError> {
+    if self.manage_wait {
+      return Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "try_reap requires manage_wait to be false".to_string(),
+      ));
+    }
+
+    if let Some(status) = self.exit_state.get() {
+      return status
+        .map(|info| Some(info.exit_code.unwrap_or(-1)))
+        .map_err(|err| PtyError::new(ErrorCode::Io, err.reason));
+    }
+
+    let child = self.child.as_mut().ok_or_else(|| {
+      PtyError::new(
+        ErrorCode::ChildExited,
+        "child process was already reaped".to_string(),
+      )
+    })?;
+
+    let status = match child.try_wait() {
+      Ok(None) => return Ok(None),
+      Ok(Some(status)) => Ok(status),
+      Err(err) => Err(err),
+    };
+
+    self.child = None;
+    let exit_info = notify_exit(
+      &self.exit_state,
+      self.options.cgroup_path.as_deref(),
+      status,
+    );
+
+    // This runs on the JS thread, which has to be free to read what the child wrote before it
+    // exited, so the fd is closed once that was drained on a thread of its own.
+    let controller = self.controller.clone();
+    thread::spawn(move || {
+      controller.wait_drained(DRAIN_TIMEOUT);
+      drop(controller.release());
+    });
+
+    exit_info
+      .map(|info| Some(info.exit_code.unwrap_or(-1)))
+      .map_err(|err| PtyError::new(ErrorCode::Io, err.reason))
+  }
+
+  /// Returns a Promise that resolves with how the child exited, once it has been reaped (by the
+  /// wait thread, or by `try_reap()` when `manage_wait` is false). Resolves immediately if the
+  /// child already exited.
+  #[napi(ts_return_type = "Promise<ExitInfo>")]
+  #[allow(dead_code)]
+  pub fn wait(&self, env: Env) -> Result<JsObject, PtyError> {
+    let exit_state = self.exit_state.clone();
+    let (deferred, promise) = env.create_deferred().map_err(napi_error)?;
+
+    thread::spawn(move || match exit_state.wait() {
+      Ok(exit_info) => deferred.resolve(move |_| Ok(exit_info)),
+      Err(err) => deferred.reject(err),
+    });
+
+    Ok(promise)
+  }
+
+  /// Returns a Promise that resolves once the child has exited *and* the controller fd was
+  /// closed, i.e. the point after which no more output will arrive.
+  ///
+  /// The controller is only closed once the child's side has hung up and everything it wrote was
+  /// read from the fd (or at most a second after the child was reaped, if the output isn't read or
+  /// a grandchild keeps the pty open), so consumers that keep reading until this resolves don't
+  /// lose the last lines of output. With `manage_wait: false` that starts with the `try_reap()`
+  /// call that reaps the child, and after `take_fd()` closing the fd is up to the caller, so this
+  /// only waits for the child to exit.
+  #[napi(ts_return_type = "Promise<void>")]
+  #[allow(dead_code)]
+  pub fn wait_closed(&self, env: Env) -> Result<JsObject, PtyError> {
+    let exit_state = self.exit_state.clone();
+    let controller = self.controller.clone();
+    let (deferred, promise) = env.create_deferred().map_err(napi_error)?;
+
+    thread::spawn(move || match exit_state.wait() {
+      Ok(_) => {
+        controller.wait_released();
+        deferred.resolve(|_| Ok(()));
+      }
+      Err(err) => deferred.reject(err),
+    });
+
+    Ok(promise)
+  }
+
+  /// Sends `SIGTERM` to the child and, if it hasn't exited after `grace_ms`, `SIGKILL`.
+  /// Resolves with the final exit code (`-1` when killed by a signal, like `on_exit`). Calling
+  /// this on a child that already exited just resolves with its exit code. Requires the wait
+  /// thread, i.e. `manage_wait` must not be false.
+  #[napi(ts_return_type = "Promise<number>")]
+  #[allow(dead_code)]
+  pub fn terminate(&self, grace_ms: u32) -> Result<AsyncTask<Terminate>, PtyError> {
+    if !self.manage_wait {
+      return Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "terminate requires manage_wait".to_string(),
+      ));
+    }
+
+    Ok(AsyncTask::new(Terminate {
+      pid: self.pid,
+      grace: Duration::from_millis(grace_ms.into()),
+      exit_state: self.exit_state.clone(),
+    }))
+  }
+
+  /// Stops the child's process group with `SIGSTOP`, e.g. to pause a runaway process without
+  /// killing it. Note that with an explicit `pgid` this affects every process in that group.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn suspend(&self) -> Result<(), PtyError> {
+    self.signal_group(libc::SIGSTOP)
+  }
+
+  /// Continues the child's process group with `SIGCONT` after `suspend()`.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn resume_process(&self) -> Result<(), PtyError> {
+    self.signal_group(libc::SIGCONT)
+  }
+
+  /// Whether the child leads its own session (`getsid(pid) == pid`), i.e. whether the `setsid`
+  /// done before `exec` took effect. False when spawned with an explicit `pgid`, or if the child
+  /// moved itself into another session.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn is_session_leader(&self) -> Result<bool, PtyError> {
+    self.ensure_running()?;
+
+    let sid = unsafe { libc::getsid(self.pid as libc::pid_t) };
+    if sid == -1 {
+      return Err(PtyError::new(
+        ErrorCode::Io,
+        format!("getsid failed: {}", Error::last_os_error()),
+      ));
+    }
+
+    Ok(sid == self.pid as libc::pid_t)
+  }
+
+  /// Issues a raw `ioctl` on the controller fd and returns its result. This is an escape hatch
+  /// for requests the crate doesn't wrap, and it is restricted to a safelist of requests that
+  /// take no argument or an integer value (e.g. `TIOCSIG`, `TCFLSH`, `TIOCSBRK`), since
+  /// pointer-taking requests can't be expressed safely from JS. Misusing even these can break
+  /// the terminal, so prefer the dedicated methods where they exist.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn ioctl(&self, request: u32, arg: Option<i32>) -> Result<i32, PtyError> {
+    if !SAFE_IOCTLS.contains(&u64::from(request)) {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("ioctl request {:#x} is not allowed", request),
+      ));
+    }
+
+    // Requests like `TIOCVHANGUP` must never reach whatever reuses the number of a closed fd.
+    self.with_controller(|fd| {
+      let res = unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg.unwrap_or(0)) };
+
+      if res == -1 {
+        return Err(PtyError::new(
+          ErrorCode::Io,
+          format!("ioctl {:#x} failed: {}", request, Error::last_os_error()),
+        ));
+      }
+
+      Ok(res)
+    })
+  }
+
+  /// Returns how many bytes of the child's output are waiting to be read from the controller fd.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn pending_output(&self) -> Result<u32, PtyError> {
+    self.with_controller(|fd| {
+      let mut pending: c_int = 0;
+      let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::FIONREAD, &mut pending as *mut _) };
+
+      if res != 0 {
+        return Err(PtyError::new(
+          ErrorCode::Io,
+          format!("ioctl FIONREAD failed: {}", Error::last_os_error()),
+        ));
+      }
+
+      Ok(pending as u32)
+    })
+  }
+
+  /// Discards the child's output that hasn't been read from the controller fd yet.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn flush_output(&self) -> Result<(), PtyError> {
+    self.require_tty()?;
+
+    self.with_controller(|fd| {
+      termios::tcflush(fd, QueueSelector::IFlush)
+        .map_err(|err| PtyError::new(ErrorCode::Io, format!("tcflush failed: {}", err)))
+    })
+  }
+
+  /// Discards input that was written to the pty but not yet read by the child, including a
+  /// partially typed line in canonical mode.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn flush_input(&self) -> Result<(), PtyError> {
+    self.require_tty()?;
+
+    // Flushing from the controller side only drops data the line discipline hasn't picked up
+    // yet, the child's pending input lives in the user side's queue.
+    let user = self.with_controller(open_user_side)?;
+
+    termios::tcflush(&user, QueueSelector::IFlush)
+      .map_err(|err| PtyError::new(ErrorCode::Io, format!("tcflush failed: {}", err)))
+  }
+
+  /// Returns the line speed (baud rate) the terminal reports, e.g. `38400` by default.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn get_speed(&self) -> Result<u32, PtyError> {
+    let termios = self.termios()?;
+    Ok(termios.output_speed())
+  }
+
+  /// Sets the line speed (baud rate) the terminal reports, for both input and output. A pty
+  /// doesn't get any slower or faster, but programs that query the speed (e.g. to pace their
+  /// output, or serial tools) behave accordingly. Only standard rates are accepted.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn set_speed(&self, baud: u32) -> Result<(), PtyError> {
+    if !BAUD_RATES.contains(&baud) {
+      return Err(PtyError::new(
+        ErrorCode::InvalidArgument,
+        format!("unsupported baud rate: {}", baud),
+      ));
+    }
+
+    let mut termios = self.termios()?;
+    termios
+      .set_speed(baud)
+      .map_err(|err| PtyError::new(ErrorCode::Io, format!("failed to set speed: {}", err)))?;
+
+    self.with_controller(|fd| {
+      termios::tcsetattr(fd, OptionalActions::Now, &termios)
+        .map_err(|err| PtyError::new(ErrorCode::Io, format!("tcsetattr failed: {}", err)))
+    })
+  }
+
+  /// Injects `data` into the pty's input queue byte by byte with `TIOCSTI`, as if it had been typed
+  /// on the terminal. Requires `allow_push_input`; see there for why this is dangerous.
+  /// The kernel additionally requires `CAP_SYS_ADMIN` (the pty isn't the caller's controlling
+  /// terminal), and Linux 6.2+ refuses it altogether unless `dev.tty.legacy_tiocsti` is enabled.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn push_input(&self, data: Buffer) -> Result<(), PtyError> {
+    if !self.options.allow_push_input.unwrap_or(false) {
+      return Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "push_input requires allow_push_input".to_string(),
+      ));
+    }
+
+    self.require_tty()?;
+
+    // Once the controller was closed its number may belong to an unrelated pty, which must never
+    // get input pushed into it.
+    let user = self.with_controller(open_user_side)?;
+
+    for byte in data.iter() {
+      let res = unsafe { libc::ioctl(user.as_raw_fd(), libc::TIOCSTI, byte as *const u8) };
+
+      if res == -1 {
+        let err = Error::last_os_error();
+        let hint = match err.raw_os_error() {
+          Some(libc::EPERM) => " (needs CAP_SYS_ADMIN)",
+          Some(libc::EIO) => " (disabled by dev.tty.legacy_tiocsti)",
+          _ => "",
+        };
+
+        return Err(PtyError::new(
+          ErrorCode::Io,
+          format!("ioctl TIOCSTI failed: {}{}", err, hint),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Transfers ownership of the controller fd to the caller, who becomes responsible for closing
+  /// it. It is no longer closed when the child exits, and methods that need it will error.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn take_fd(&mut self) -> Result<c_int, PtyError> {
+    self.ensure_fd_owned()?;
+    if self.forwards_output {
+      return Err(PtyError::new(
+        ErrorCode::Unsupported,
+        "controller fd can't be taken while on_data reads it".to_string(),
+      ));
+    }
+
+    let file = self.controller.release().ok_or_else(|| {
+      PtyError::new(
+        ErrorCode::ChildExited,
+        "controller fd was closed after the child exited".to_string(),
+      )
+    })?;
+
+    self.fd_taken = true;
+    Ok(file.into_raw_fd())
+  }
+
+  /// Whether ownership of the controller fd was transferred with `take_fd`.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn fd_taken(&self) -> bool {
+    self.fd_taken
+  }
+
+  /// Like `take_fd`, but also hands over a fresh fd for the pty's user side (opened with
+  /// `O_NOCTTY`), e.g. to re-parent the tty. The parent's original copy of the user side is closed
+  /// right after spawning, so this is a new handle; `user` is unset in pipe mode, where there is
+  /// no tty. Either both fds are transferred or neither is. Note that while the caller keeps the
+  /// user fd open, the controller won't see a hangup when the child exits.
+  #[napi]
+  #[allow(dead_code)]
+  pub fn take_fds(&mut self) -> Result<TakenFds, PtyError> {
+    let user = match self.tty_dev {
+      Some(_) => Some(self.with_controller(open_user_side)?),
+      None => None,
+    };
+
+    Ok(TakenFds {
+      controller: self.take_fd()?,
+      user: user.map(IntoRawFd::into_raw_fd),
+    })
+  }
+
+  /// The command the child was spawned with, as passed in the options.
+  #[napi(getter)]
+  #[allow(dead_code)]
+  pub fn command(&self) -> String {
+    self.options.command.clone()
+  }
+
+  /// The arguments the child was spawned with.
+  #[napi(getter)]
+  #[allow(dead_code)]
+  pub fn args(&self) -> Vec<String> {
+    self.options.args.clone()
+  }
+
+  /// The child's initial working directory, resolved to an absolute path.
+  #[napi(getter)]
+  #[allow(dead_code)]
+  pub fn dir(&self) -> String {
+    self.dir.clone()
+  }
+}
+
+/// Spawns a child like `new Pty(opts)`, reads all of its output until EOF, waits for it to exit
+/// and returns both. This **blocks** the calling thread (and so the JS event loop) until then,
+/// which is fine for scripts but not for servers. The child gets no input (in pty mode it should
+/// not try to read any), `manage_wait` is ignored, and `on_exit` (if any) still fires once the
+/// child is reaped. EOF only arrives once every process holding the pty open has closed it,
+/// including backgrounded grandchildren. Only `fd` is read, so stderr (and, in pty mode, stdout)
+/// can't be a pipe of its own.
+#[napi]
+#[allow(dead_code)]
+fn run(opts: PtyOptions) -> Result<RunResult, PtyError> {
+  if opts.on_data.is_some() {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      "on_data is not supported by run".to_string(),
+    ));
+  }
+  // Only `fd` is read, which wouldn't see any of the child's stdout. A stderr pipe wouldn't be
+  // read at all, and the child would block forever once it's full.
+  if SpawnMode::parse(opts.mode.as_deref())? == SpawnMode::Pty
+    && opts.stdout.as_deref() == Some("pipe")
+  {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      "stdout \"pipe\" in pty mode is not supported by run".to_string(),
+    ));
+  }
+  if StdioMode::parse_stderr(&opts)? == Some(StdioMode::Pipe) {
+    return Err(PtyError::new(
+      ErrorCode::InvalidArgument,
+      "stderr \"pipe\" is not supported by run".to_string(),
+    ));
+  }
+
+  let max_output_bytes = max_output_bytes(&opts)?;
+  let kill_on_output_limit = opts.kill_on_output_limit.unwrap_or(false);
+  let mut output_log = opts
+    .output_log_path
+    .as_deref()
+    .map(open_output_log)
+    .transpose()?;
+
+  let mut pty = Pty::new(PtyOptions {
+    manage_wait: Some(false),
+    output_log_path: None,
+    max_output_bytes: None,
+    ..opts
+  })?;
+
+  // In pipe mode this gives the child EOF on stdin.
+  drop(pty.input.take());
+
+  let mut output = Vec::new();
+  let mut truncated = false;
+  let mut buf = [0u8; 4096];
+  let mut read_err = None;
+  loop {
+    let res = unsafe { libc::read(pty.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+
+    if res > 0 {
+      let data = &buf[..res as usize];
+      tee_output(&mut output_log, data);
+      let room = max_output_bytes - output.len();
+      output.extend_from_slice(&data[..data.len().min(room)]);
+
+      if data.len() > room && !truncated {
+        truncated = true;
+        if kill_on_output_limit {
+          // Keep reading until EOF, so the child doesn't block on a full buffer before it dies.
+          let _ = pty.signal_group(libc::SIGKILL);
+        }
+      }
+      continue;
+    }
+    if res == 0 {
+      break;
+    }
+
+    let err = Error::last_os_error();
+    match err.kind() {
+      ErrorKind::Interrupted => {}
+      ErrorKind::WouldBlock => {
+        if let Err(err) = poll_fd(pty.fd, libc::POLLIN) {
+          read_err = Some(format!("poll failed: {}", err));
+          break;
+        }
+      }
+      // Reading the controller fails with `EIO` once the child's side was closed.
+      _ if err.raw_os_error() == Some(libc::EIO) => break,
+      _ => {
+        read_err = Some(format!("read failed: {}", err));
+        break;
+      }
+    }
+  }
+
+  // Nobody else reaps the child, so even when reading failed it is killed and waited for here.
+  if read_err.is_some() {
+    let _ = pty.signal_group(libc::SIGKILL);
+  }
+  let mut child = pty
+    .child
+    .take()
+    .expect("child is kept with manage_wait: false");
+  let exit_info = notify_exit(
+    &pty.exit_state,
+    pty.options.cgroup_path.as_deref(),
+    child.wait(),
+  )
+  .map_err(|err| PtyError::new(ErrorCode::Io, err.reason))?;
+  if let Some(reason) = read_err {
+    return Err(PtyError::new(ErrorCode::Io, reason));
+  }
+
+  Ok(RunResult {
+    exit_code: exit_info.exit_code.unwrap_or(-1),
+    output: output.into(),
+    truncated,
+  })
+}
+
+/// Returns how many `Pty` objects are currently alive, i.e. were constructed and not yet garbage
+/// collected. A number that keeps growing in a long-running process points at `Pty`s (and their
+/// fds) being kept around after their child exited.
+#[napi]
+#[allow(dead_code)]
+fn live_pty_count() -> u32 {
+  LIVE_PTYS.load(Ordering::Relaxed)
 }